@@ -0,0 +1,105 @@
+use ic_cdk::api::management_canister::main::raw_rand;
+use serde_json::json;
+use telegram_bot_raw::{Message as TelegramMessage, MessageChat};
+
+use crate::bot::{send_message, send_message_to};
+use crate::memory::{access_reviewer_ids, add_access_request, add_invite, decide_access_request, set_role, take_invite};
+use crate::telegram::{access_decision_keyboard, access_request_keyboard, bot_username, call_telegram, ensure_secret};
+use crate::types::{HttpResponse, Role};
+
+/// Answers users who are neither whitelisted nor have a role. Only private
+/// chats get an answer: an invite link (`/start <code>`) lets them in,
+/// anything else gets the "Request access" button.
+pub async fn handle_unknown_user(message: TelegramMessage, text: String) -> Option<HttpResponse> {
+    if !matches!(message.chat, MessageChat::Private(_)) {
+        return None;
+    }
+    let user_id: i64 = message.from.id.into();
+    ensure_secret().await;
+    if let Some(code) = text.strip_prefix("/start ").and_then(|code| parse_invite(code.trim())) {
+        if take_invite(code) {
+            set_role(user_id, Some(Role::User), "invite".to_string());
+            return Some(send_message(
+                message.chat,
+                0,
+                "Welcome! Your invite worked. Try /help or send me a prompt.".to_string(),
+                None,
+            ));
+        }
+        return Some(send_message(
+            message.chat,
+            0,
+            "This invite is not valid any more.".to_string(),
+            Some(access_request_keyboard(user_id)),
+        ));
+    }
+    Some(send_message(
+        message.chat,
+        0,
+        "You are not allowed to use this bot yet. Ask an admin for access:".to_string(),
+        Some(access_request_keyboard(user_id)),
+    ))
+}
+
+/// Forwards the request to every admin and moderator, which takes an
+/// outcall each as the webhook reply can only reach one chat. Returns what
+/// to tell the requester.
+pub async fn request_access(user_id: i64, username: String) -> String {
+    let reviewers = access_reviewer_ids();
+    if reviewers.is_empty() {
+        return "No admin can be reached right now, please try again later.".to_string();
+    }
+    if !add_access_request(user_id, username.clone()) {
+        return "Your request is waiting for an admin.".to_string();
+    }
+    let params = json!({
+        "text": format!("@{} ({}) asks for access.", username, user_id),
+        "reply_markup": access_decision_keyboard(user_id),
+    });
+    for chat_id in reviewers {
+        let mut params = params.clone();
+        params["chat_id"] = json!(chat_id);
+        if let Err(err) = call_telegram("sendMessage", params).await {
+            ic_cdk::println!("Access request to {} failed - {}", chat_id, err);
+        }
+    }
+    "Your request was sent to the admins. You will hear back here.".to_string()
+}
+
+/// An admin or moderator pressed Approve or Deny: lets the requester in or
+/// not. Returns what to tell them and the message telling the requester.
+pub fn decide_access(approved: bool, requester: i64, admin_id: i64) -> (String, Option<HttpResponse>) {
+    let Some(username) = decide_access_request(requester, approved) else {
+        return (format!("The request of {} was already decided.", requester), None);
+    };
+    let (decision, reply) = if approved {
+        set_role(requester, Some(Role::User), format!("telegram:{}", admin_id));
+        ("approved", "Your access request was approved. Try /help or send me a prompt.")
+    } else {
+        ("denied", "Your access request was denied.")
+    };
+    (
+        format!("@{} ({}) was {}.", username, requester, decision),
+        Some(send_message_to(requester, 0, reply.to_string(), None)),
+    )
+}
+
+fn parse_invite(code: &str) -> Option<[u8; 8]> {
+    hex::decode(code).ok()?.try_into().ok()
+}
+
+/// Makes a single-use invite code and the link that redeems it.
+pub async fn invite_text(admin_id: i64) -> String {
+    let bytes = match raw_rand().await {
+        Ok((bytes,)) => bytes,
+        Err((r, m)) => return format!("'Could not make an invite: {:?} {}'", r, m),
+    };
+    let mut code = [0u8; 8];
+    code.copy_from_slice(&bytes[..8]);
+    add_invite(code, admin_id);
+    format!(
+        "'Send this single-use invite link:\nhttps://t.me/{}?start={}'",
+        bot_username().await,
+        hex::encode(code)
+    )
+}
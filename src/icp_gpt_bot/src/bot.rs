@@ -1,120 +1,814 @@
-use crate::gpt::call_chatgpt;
-use crate::types::{Form, Message, MessageType};
+use crate::access::{decide_access, invite_text, request_access};
+use crate::budget::check_budget;
+use crate::commands::{help_text, parse_command, register_commands, CommandKind, ParsedCommand, ParsedText, Permission};
+use crate::gpt::{call_chatgpt, call_image, ChatRequest, ImageRequest, LlmError, DEFAULT_MAX_TOKENS};
+use crate::credits::{charge_credits, check_credits, claim_text, credits_text, topup_text};
+use crate::ledger::{lookup_account, parse_account};
+use crate::premium::premium_invoice;
+use crate::privacy::{export_documents, forget_prompt_text, forget_text, history_text};
+use crate::telegram::{
+    answer_callback_query, answer_keyboard, bot_username, callback_answer_reply, ensure_secret, is_chat_admin,
+    forget_keyboard, read_callback, CallbackAction,
+};
+use crate::tools::{chat_with_tools, ToolContext};
+use crate::types::{
+    CachedResponse, ChatSettings, Conversation, Form, Message, MessageType, Role, Usage, UsagePeriod, UsageRecord, UsageSubject,
+};
 use crate::{
-    memory::{add_new_messages, get_followed_messages, get_latest_messages},
+    memory::{
+        add_incognito_message, add_new_messages, clear_incognito_messages, clear_messages, find_shortcuts, get_chat_model, get_chat_prompt,
+        get_admin_id, get_backend, get_chat_settings, get_cached_response, get_followed_messages, get_incognito_messages, get_inline_cache, get_latest_messages, get_model, get_premium_config, is_premium,
+        get_prompt, get_response_cache, get_retry_policy, get_shortcut, get_usage, get_role, take_notices, get_user_chat_model, get_user_tier, is_allowed, is_incognito, list_roles, put_cached_response, record_usage, remove_message,
+        set_chat_settings, set_incognito, set_inline_cache, set_role, take_inline_rate, take_rate_token, until_next_day,
+    },
     types::{HeaderField, HttpResponse},
 };
+use ic_cdk::api::management_canister::main::raw_rand;
 use regex::Regex;
 use serde_json::json;
 use serde_json::Value;
-use telegram_bot_raw::{MessageChat, SendMessage, ParseMode};
+use sha2::{Digest, Sha256};
+use telegram_bot_raw::{
+    AnswerInlineQuery, CallbackQuery, CallbackQueryId, ChatId, InlineKeyboardMarkup, InlineQuery, InlineQueryResult,
+    InlineQueryResultArticle, InputTextMessageContent, Message as TelegramMessage, MessageChat,
+    MessageEntity, MessageOrChannelPost, ParseMode, SendMessage,
+};
 
-pub async fn handle_message(username: String, chat: MessageChat, text: String) -> HttpResponse {
-    let timestamp = ic_cdk::api::time();
+/// Inline answers are kept short.
+const INLINE_MAX_TOKENS: u32 = 200;
 
-    let response = if text.contains("/") {
-        if text == "/start".to_string() {
-            "'Hello! I am a Telegram Bot on Internet Computer using ChatGPT.\nTry /help to get my information.\nTry to send prompt for chat completion\nTry /imagine+prompt for image generation.\n'".to_string()
-        } else if text == "/help".to_string() {
+pub async fn handle_message(
+    message: TelegramMessage,
+    text: String,
+    entities: Vec<MessageEntity>,
+    thread_id: i64,
+) -> Option<HttpResponse> {
+    let bot_username = bot_username().await;
+    let is_private = matches!(message.chat, MessageChat::Private(_));
+    let user_id: i64 = message.from.id.into();
+    let username = message.from.username.clone().unwrap_or_default();
+    let role = get_role(user_id, &username).unwrap_or(Role::User);
+    let conversation = Conversation {
+        chat_id: message.chat.id().into(),
+        thread_id,
+    };
+    ensure_secret().await;
+
+    let (response, keyboard) = match parse_command(&text, &entities, &bot_username) {
+        ParsedText::NotForUs => return None,
+        ParsedText::Unknown(name, addressed) => {
+            if !is_private && !addressed {
+                // probably meant for another bot in the group
+                return None;
+            }
+            (format!("'Invalid Command /{}. Try /help.'", name), None)
+        }
+        // answered with an invoice or a file rather than a message; both are
+        // open to every user
+        ParsedText::Command(command) if command.command.kind == CommandKind::Premium => {
+            return Some(premium_invoice(message.chat, conversation, user_id));
+        }
+        ParsedText::Command(command) if command.command.kind == CommandKind::Export => {
+            return Some(export_documents(message.chat, conversation, user_id));
+        }
+        ParsedText::Command(command) => {
+            if has_permission(command.command.permission, conversation, user_id, role).await {
+                run_command(command, conversation, user_id, username, role).await
+            } else {
+                ("'You are not allowed to use this command.'".to_string(), None)
+            }
+        }
+        ParsedText::Text => {
+            let text = addressed_text(&message, text, &bot_username)?;
+            if !is_incognito(user_id) {
+                ic_cdk::println! {"{}", username};
+            }
+            let is_follow = text.starts_with('+');
+            let response =
+                core_action(MessageType::Chat, conversation, user_id, username, text, is_follow, false).await;
+            (response, Some(answer_keyboard(user_id)))
+        }
+    };
+    Some(send_message(
+        message.chat,
+        thread_id,
+        response[1..response.len() - 1].to_string(),
+        keyboard,
+    ))
+}
+
+async fn has_permission(permission: Permission, conversation: Conversation, user_id: i64, role: Role) -> bool {
+    match permission {
+        Permission::User => true,
+        Permission::ChatAdmin => role >= Role::Moderator || is_chat_admin(conversation.chat_id, user_id).await,
+        Permission::Moderator => role >= Role::Moderator,
+        Permission::Admin => role == Role::Admin,
+    }
+}
+
+async fn run_command(
+    command: ParsedCommand,
+    conversation: Conversation,
+    user_id: i64,
+    username: String,
+    role: Role,
+) -> (String, Option<InlineKeyboardMarkup>) {
+    if !command.command.usage.is_empty() && command.args().is_empty() {
+        return (
             format!(
-                "'This is a Telegram bot on the Internet Computer!\nMy canister id: {}\nLocal time is {}ns.\nMy cycle balance is {}\nFind me on telegram:\nhttps://t.me/canister_ai_bot\nFind me on browser:\nhttps://{}.raw.icp0.io/\n'",
-                ic_cdk::id(),
-                timestamp,
-                ic_cdk::api::canister_balance(),
-                ic_cdk::id()
+                "'Usage: /{} {}'",
+                command.command.name, command.command.usage
+            ),
+            None,
+        );
+    }
+    match command.command.kind {
+        CommandKind::Start => (
+            "'Hello! I am a Telegram Bot on Internet Computer using ChatGPT.\nTry /help to get my information.\nTry to send prompt for chat completion\nTry /imagine+prompt for image generation.\n'".to_string(),
+            None,
+        ),
+        CommandKind::Help => {
+            let permission = match role {
+                Role::Admin => Permission::Admin,
+                Role::Moderator => Permission::Moderator,
+                _ => Permission::ChatAdmin,
+            };
+            (
+                format!(
+                    "'This is a Telegram bot on the Internet Computer!\nMy canister id: {}\nLocal time is {}ns.\nMy cycle balance is {}\nFind me on telegram:\nhttps://t.me/canister_ai_bot\nFind me on browser:\nhttps://{}.raw.icp0.io/\n\n{}'",
+                    ic_cdk::id(),
+                    ic_cdk::api::time(),
+                    ic_cdk::api::canister_balance(),
+                    ic_cdk::id(),
+                    help_text(permission)
+                ),
+                None,
             )
-        } else if text == "/retry".to_string() {
-            core_action(MessageType::Chat, username, "".to_string(), false, true).await
-        } else if text == "/imagine".to_string() {
-            "'Send prompt after /Imagine\nLike /imagine a cute cat'".to_string()
-        } else if text.contains("/imagine") {
-            let prompt = text.strip_prefix("/imagine").unwrap();
+        }
+        CommandKind::Retry => (
+            core_action(MessageType::Chat, conversation, user_id, username, "".to_string(), false, true).await,
+            Some(answer_keyboard(user_id)),
+        ),
+        CommandKind::Imagine => (
             core_action(
                 MessageType::Image,
+                conversation,
+                user_id,
                 username,
-                prompt.to_string(),
+                command.argument,
                 false,
                 false,
             )
-            .await
-        } else {
-            "'Invalid Command.'".to_string()
+            .await,
+            Some(answer_keyboard(user_id)),
+        ),
+        CommandKind::Topup => (topup_text(user_id), None),
+        CommandKind::Claim => (claim_text(user_id).await, None),
+        CommandKind::Credits => (credits_text(user_id), None),
+        CommandKind::Premium | CommandKind::Export => unreachable!("answered by handle_message"),
+        CommandKind::History => (history_text(conversation, user_id), None),
+        CommandKind::Incognito => match command.argument.to_lowercase().as_str() {
+            "on" => {
+                set_incognito(user_id, true);
+                (
+                    "'Incognito is on. Nothing you send is stored or logged; follow-ups are remembered for 10 minutes.'"
+                        .to_string(),
+                    None,
+                )
+            }
+            "off" => {
+                set_incognito(user_id, false);
+                ("'Incognito is off.'".to_string(), None)
+            }
+            _ => ("'Usage: /incognito <on|off>'".to_string(), None),
+        },
+        CommandKind::Forget => (forget_prompt_text(), Some(forget_keyboard(user_id))),
+        CommandKind::Settings
+        | CommandKind::SetPrompt
+        | CommandKind::SetModel
+        | CommandKind::ResetSettings => (
+            configure_chat(conversation, command.command.kind, command.argument, user_id, role),
+            None,
+        ),
+        CommandKind::Usage => match command.args().first() {
+            None => (usage_text(conversation, user_id), None),
+            Some(_) if role < Role::Moderator => ("'Only moderators can see the usage of others.'".to_string(), None),
+            Some(target) => match target.parse::<i64>() {
+                Ok(target) => (user_usage_text(target), None),
+                Err(_) => ("'Usage: /usage [user id]'".to_string(), None),
+            },
+        },
+        CommandKind::Ban | CommandKind::Unban | CommandKind::SetRole => {
+            (change_role(command.command.kind, command.args(), user_id, role), None)
         }
-    } else {
-        ic_cdk::println! {"{}", username};
-        let is_follow = if text.starts_with('+') { true } else { false };
-        core_action(MessageType::Chat, username, text, is_follow, false).await
+        CommandKind::Roles => (roles_text(), None),
+        CommandKind::Invite => (invite_text(user_id).await, None),
+        CommandKind::Balance => {
+            let summary = match parse_account(&command.argument) {
+                Ok(account) => lookup_account(account, 5).await,
+                Err(err) => Err(err),
+            };
+            match summary {
+                Ok(summary) => (format!("'{}'", summary.to_text()), None),
+                Err(err) => (format!("'Could not look up the account: {}'", err), None),
+            }
+        }
+        CommandKind::SetCommands => match register_commands().await {
+            Ok(()) => ("'Commands registered.'".to_string(), None),
+            Err(err) => (format!("'Failed to register commands: {}'", err), None),
+        },
+    }
+}
+
+fn usage_text(conversation: Conversation, user_id: i64) -> String {
+    let mut lines = vec![
+        format!("Today: {}", format_usage(get_usage(UsageSubject::User, user_id, UsagePeriod::Day))),
+        format!(
+            "This month: {}",
+            format_usage(get_usage(UsageSubject::User, user_id, UsagePeriod::Month))
+        ),
+    ];
+    if conversation.chat_id != user_id {
+        let chat_usage = get_usage(UsageSubject::Chat, conversation.chat_id, UsagePeriod::Month);
+        lines.push(format!("This chat this month: {}", format_usage(chat_usage)));
+    }
+    format!("'Your usage\n{}'", lines.join("\n"))
+}
+
+fn user_usage_text(user_id: i64) -> String {
+    format!(
+        "'Usage of {}\nToday: {}\nThis month: {}'",
+        user_id,
+        format_usage(get_usage(UsageSubject::User, user_id, UsagePeriod::Day)),
+        format_usage(get_usage(UsageSubject::User, user_id, UsagePeriod::Month))
+    )
+}
+
+/// `/ban`, `/unban` and `/role`. Moderators can only ban and unban users
+/// below them, which leaves out the admin of the init argument; nobody can
+/// change their own role.
+fn change_role(kind: CommandKind, args: Vec<&str>, user_id: i64, role: Role) -> String {
+    let target = match args.first().map(|target| target.parse::<i64>()) {
+        Some(Ok(target)) => target,
+        _ => return "'The first argument must be a user id.'".to_string(),
+    };
+    if target == user_id {
+        return "'You cannot change your own role.'".to_string();
+    }
+    if get_admin_id() == Some(target) {
+        return "'The admin of the bot is set by the controllers of the canister.'".to_string();
+    }
+    let current = get_role(target, "");
+    let new_role = match kind {
+        CommandKind::Ban => Some(Role::Banned),
+        CommandKind::Unban if current != Some(Role::Banned) => {
+            return format!("'{} is not banned.'", target);
+        }
+        CommandKind::Unban => Some(Role::User),
+        _ => match args.get(1).copied() {
+            Some("none") => None,
+            Some(name) => match Role::from_name(name) {
+                Some(role) => Some(role),
+                None => return format!("'Unknown role {}.'", name),
+            },
+            None => return "'Usage: /role <user id> <admin|moderator|premium|user|banned|none>'".to_string(),
+        },
     };
-    send_message(chat, response[1..response.len() - 1].to_string())
+    if role != Role::Admin && current.is_some_and(|current| current >= Role::Moderator) {
+        return "'Only admins can change the role of moderators and admins.'".to_string();
+    }
+    set_role(target, new_role, format!("telegram:{}", user_id));
+    match new_role {
+        Some(new_role) => format!("'{} is now {}.'", target, new_role.name()),
+        None => format!("'{} has no role any more.'", target),
+    }
+}
+
+fn roles_text() -> String {
+    let lines: Vec<String> = list_roles()
+        .into_iter()
+        .map(|user_role| format!("{}: {}", user_role.user_id, user_role.role.name()))
+        .collect();
+    if lines.is_empty() {
+        return "'Nobody has a role yet.'".to_string();
+    }
+    format!("'{}'", lines.join("\n"))
+}
+
+fn format_usage(usage: UsageRecord) -> String {
+    format!(
+        "{} chats, {} images, {} tokens, {:.3}B cycles",
+        usage.requests,
+        usage.images,
+        usage.prompt_tokens + usage.completion_tokens,
+        usage.cycles as f64 / 1e9
+    )
+}
+
+/// In groups we only answer mentions of the bot and replies to the bot's
+/// own messages. Returns the text without the `@botname` part, or `None`
+/// when the message is not meant for us.
+fn addressed_text(message: &TelegramMessage, text: String, bot_username: &str) -> Option<String> {
+    if let MessageChat::Private(_) = message.chat {
+        return Some(text);
+    }
+    if bot_username.is_empty() {
+        return None;
+    }
+    let mention = Regex::new(&format!(r"(?i)@{}\b", regex::escape(bot_username))).unwrap();
+    if mention.is_match(&text) {
+        return Some(mention.replace_all(&text, "").trim().to_string());
+    }
+    match message.reply_to_message.as_deref() {
+        Some(MessageOrChannelPost::Message(reply))
+            if reply
+                .from
+                .username
+                .as_deref()
+                .is_some_and(|username| username.eq_ignore_ascii_case(bot_username)) =>
+        {
+            Some(text)
+        }
+        _ => None,
+    }
+}
+
+/// Shows or changes the settings of a chat. Inside a forum topic they only
+/// apply to that topic.
+fn configure_chat(conversation: Conversation, kind: CommandKind, argument: String, user_id: i64, role: Role) -> String {
+    let mut settings = get_chat_settings(conversation);
+    match kind {
+        CommandKind::SetPrompt => settings.prompt = Some(argument),
+        CommandKind::SetModel => {
+            let models = selectable_models(user_id, role);
+            if !models.contains(&argument) {
+                return format!("'Unknown model. Available models: {}'", models.join(", "));
+            }
+            settings.model = Some(argument)
+        }
+        CommandKind::ResetSettings => settings = ChatSettings::default(),
+        _ => {}
+    }
+    set_chat_settings(conversation, settings);
+    format!(
+        "'System prompt: {}\nModel: {}'",
+        escape_html(&get_chat_prompt(conversation)),
+        get_chat_model(conversation)
+    )
+}
+
+/// The default model, the backend's models and, for premium users and
+/// moderators, the premium model.
+fn selectable_models(user_id: i64, role: Role) -> Vec<String> {
+    let premium_model = get_premium_config().model;
+    let may_use_premium = role >= Role::Moderator || is_premium(user_id);
+    let mut models = vec![get_model()];
+    for model in get_backend().models.into_iter().chain(premium_model.clone()) {
+        if !models.contains(&model) && (may_use_premium || premium_model.as_ref() != Some(&model)) {
+            models.push(model);
+        }
+    }
+    models
+}
+
+/// Unknown users only get as far as the "Request access" button.
+pub async fn handle_callback(query: CallbackQuery, thread_id: i64) -> Option<HttpResponse> {
+    let (chat, message_id) = match query.message {
+        Some(MessageOrChannelPost::Message(message)) => (message.chat, message.id),
+        _ => return None,
+    };
+    let user_id: i64 = query.from.id.into();
+    let username = query.from.username.unwrap_or_default();
+    // the signed user id is the requester on access buttons and the owner
+    // of the thread on all others
+    let action = match query.data.as_deref().and_then(read_callback) {
+        Some((action @ (CallbackAction::Approve | CallbackAction::Deny), requester))
+            if get_role(user_id, &username) >= Some(Role::Moderator) =>
+        {
+            let approved = action == CallbackAction::Approve;
+            let (toast, reply) = decide_access(approved, requester, user_id);
+            return Some(answer_with_toast(query.id, toast, reply).await);
+        }
+        Some((CallbackAction::RequestAccess, owner)) if owner == user_id => CallbackAction::RequestAccess,
+        Some((action, owner)) if owner == user_id && is_allowed(user_id, &username) => action,
+        _ => {
+            return Some(callback_answer_reply(query.id, Some("This button is not for you.".to_string())));
+        }
+    };
+    if action == CallbackAction::RequestAccess {
+        let toast = request_access(user_id, username).await;
+        return Some(callback_answer_reply(query.id, Some(toast)));
+    }
+    answer_callback_query(query.id.clone(), None).await;
+
+    let conversation = Conversation {
+        chat_id: chat.id().into(),
+        thread_id,
+    };
+    let response = match action {
+        CallbackAction::Retry => {
+            core_action(MessageType::Chat, conversation, user_id, username, "".to_string(), false, true).await
+        }
+        CallbackAction::Continue => {
+            let prompt = "Continue.".to_string();
+            core_action(MessageType::Chat, conversation, user_id, username, prompt, true, false).await
+        }
+        CallbackAction::NewChat => {
+            if is_incognito(user_id) {
+                clear_incognito_messages(conversation, user_id);
+            } else {
+                clear_messages(conversation, user_id);
+            }
+            return Some(send_message(
+                chat,
+                thread_id,
+                "Started a new chat. Send me a prompt.".to_string(),
+                None,
+            ));
+        }
+        CallbackAction::Imagine => match current_thread(conversation, user_id).1 {
+            Some(message) => {
+                let prompt = message.question;
+                core_action(MessageType::Image, conversation, user_id, username, prompt, false, false).await
+            }
+            None => "'There is not a previous message.'".to_string(),
+        },
+        CallbackAction::Forget | CallbackAction::KeepData => {
+            let text = if action == CallbackAction::Forget {
+                forget_text(user_id)
+            } else {
+                "Nothing was deleted.".to_string()
+            };
+            return Some(webhook_reply(
+                "editMessageText",
+                json!({ "chat_id": chat.id(), "message_id": message_id, "text": text }),
+            ));
+        }
+        CallbackAction::RequestAccess | CallbackAction::Approve | CallbackAction::Deny => return None,
+    };
+    Some(send_message(
+        chat,
+        thread_id,
+        response[1..response.len() - 1].to_string(),
+        Some(answer_keyboard(user_id)),
+    ))
+}
+
+/// Shows `toast` to whoever pressed the button. It goes out as the webhook
+/// reply, unless `reply` takes that.
+async fn answer_with_toast(query_id: CallbackQueryId, toast: String, reply: Option<HttpResponse>) -> HttpResponse {
+    match reply {
+        Some(reply) => {
+            answer_callback_query(query_id, Some(toast)).await;
+            reply
+        }
+        None => callback_answer_reply(query_id, Some(toast)),
+    }
+}
+
+/// Answers `@bot query` from any chat. A completion is only requested once
+/// the query ends like a sentence, so partially typed queries cost nothing.
+pub async fn handle_inline_query(query: InlineQuery) -> HttpResponse {
+    let text = query.query.trim().to_string();
+    let user_id: i64 = query.from.id.into();
+    let mut results: Vec<InlineQueryResult> = vec![];
+
+    let shortcut = get_shortcut(&text);
+    let is_complete = shortcut.is_some() || text.ends_with(['?', '.', '!']);
+    let prompt = shortcut.unwrap_or_else(|| text.clone());
+    if !text.is_empty() && is_complete {
+        let cache_key = prompt.to_lowercase();
+        let answer = match get_inline_cache(&cache_key) {
+            Some(answer) => Ok(answer),
+            // inline answers have no chat, so only the user's limits apply
+            None => match check_limits(user_id, 0, MessageType::Chat, INLINE_MAX_TOKENS) {
+                Err(message) => Err(message),
+                Ok(()) if take_inline_rate(user_id) => {
+                    let key = format!("Inline-{}-{}", prompt, ic_cdk::api::time());
+                    let mut cycles = 0;
+                    let result = call_chatgpt(&make_inline_request(prompt.clone()), key, &mut cycles).await;
+                    let usage = result.as_ref().ok().map(|completion| completion.usage);
+                    record_usage(user_id, 0, MessageType::Chat, usage, cycles);
+                    charge_credits(user_id, cycles, usage);
+                    match result {
+                        Ok(completion) => {
+                            let answer = convert_to_telegram_format(&completion.text(), "html");
+                            if !is_incognito(user_id) {
+                                set_inline_cache(cache_key, user_id, answer.clone());
+                            }
+                            Ok(answer)
+                        }
+                        Err(err) => Err(err.user_message()),
+                    }
+                }
+                Ok(()) => Err("Too many requests, try again in a minute.".to_string()),
+            },
+        };
+        match answer {
+            Ok(answer) => results.push(inline_article("answer", &prompt, answer)),
+            Err(message) => results.push(inline_article("error", &message, message.clone())),
+        }
+    }
+
+    find_shortcuts(&text)
+        .into_iter()
+        .take(10)
+        .enumerate()
+        .for_each(|(index, (shortcut, prompt))| {
+            // shortcut names can be longer than the 64 bytes an id may have
+            results.push(inline_article(&format!("shortcut-{}", index), &shortcut, prompt));
+        });
+
+    let mut answer = AnswerInlineQuery::new(query.id, results);
+    answer.cache_time(60).is_personal();
+    webhook_reply("answerInlineQuery", serde_json::to_value(answer).unwrap())
+}
+
+fn inline_article(id: &str, title: &str, text: String) -> InlineQueryResult {
+    let mut article = InlineQueryResultArticle::new(
+        id,
+        title,
+        InputTextMessageContent {
+            message_text: text.clone(),
+            parse_mode: Some(ParseMode::Html),
+            disable_web_page_preview: true,
+        },
+    );
+    article.description(text);
+    article.into()
+}
+
+/// The request behind an answer, kept so it can be sent again.
+#[derive(Clone)]
+enum CompletionRequest {
+    Chat(ChatRequest),
+    Image(ImageRequest),
+}
+
+/// An answer still to be stored once the completion succeeds, possibly
+/// after some background retries.
+#[derive(Clone)]
+struct PendingCompletion {
+    key: String,
+    conversation: Conversation,
+    user_id: i64,
+    username: String,
+    types: MessageType,
+    timestamp: u64,
+    prompt: String,
+    is_follow: bool,
+    /// Key of the answer a retry replaces.
+    replaces: Option<String>,
+    /// Set when the answer may be shared through the response cache.
+    cache_key: Option<[u8; 32]>,
+    /// Keep the turn in memory only, for a few minutes.
+    incognito: bool,
+    request: CompletionRequest,
+}
+
+/// A successful completion.
+struct Answer {
+    reply: String,
+    usage: Usage,
+    /// The model that answered.
+    model: String,
+    cycles: u128,
+    /// Tool results depend on the user and the moment, so such answers
+    /// are not shared through the response cache.
+    used_tools: bool,
 }
 
 pub async fn core_action(
     types: MessageType,
+    conversation: Conversation,
+    user_id: i64,
     username: String,
     prompt: String,
     is_follow: bool,
     is_retry: bool,
 ) -> String {
     let timestamp = ic_cdk::api::time();
-    let _latest_message = get_latest_messages(username.clone());
-    let followed_message = get_followed_messages(username.clone());
-
-    let (uri, request_body, key) = if is_retry {
-        if _latest_message.is_none() {
-            return "There is not a previous message.".to_string();
-        } else {
-            let latest_message = _latest_message.unwrap();
-            let key = format!(
-                "{:#?}-{}-{}",
-                latest_message.types, latest_message.question, timestamp
-            );
-            if latest_message.types == MessageType::Image {
-                // retry for image generation
-                let request_body = json!({
-                    "model": "dall-e-3",
-                    "prompt": latest_message.question,
-                    "n": 1,
-                })
-                .to_string();
-                ("image", request_body, key)
-            } else {
-                //retry for chat completion
-                let request_body = make_chat_request(followed_message, is_retry, prompt.clone());
-                ("chat", request_body, key)
-            }
-        }
+    let incognito = is_incognito(user_id);
+    let (followed_message, latest_message) = current_thread(conversation, user_id);
+    let (key, types, prompt, is_follow) = if is_retry {
+        let latest_message = match latest_message.clone() {
+            Some(latest_message) => latest_message,
+            None => return "'There is not a previous message.'".to_string(),
+        };
+        let key = format!(
+            "{:#?}-{}-{}",
+            latest_message.types, latest_message.question, timestamp
+        );
+        (
+            key,
+            latest_message.types,
+            latest_message.question,
+            latest_message.is_follow,
+        )
     } else {
-        if types == MessageType::Image {
-            let key = format!("{:#?}-{}-{}", types, prompt.clone(), timestamp);
-            let request_body = json!({
-                "model": "dall-e-3",
-                "prompt": prompt.clone(),
-                "n": 1,
-            })
-            .to_string();
-            ("image", request_body, key)
-        } else {
-            let key = format!("{:#?}-{}-{}", types, prompt.clone(), timestamp);
-            let request_body = if is_follow {
-                make_chat_request(followed_message, is_retry, prompt.clone())
-            } else {
-                make_chat_request(vec![], is_retry, prompt.clone())
-            };
-            ("chat", request_body, key)
-        }
+        let key = format!("{:#?}-{}-{}", types, prompt.clone(), timestamp);
+        (key, types, prompt, is_follow)
     };
-    let mut reply = call_chatgpt(uri, request_body.clone(), key.clone()).await;
-    if reply == "Rate exceeded.".to_string() {
-        reply = call_chatgpt("image", request_body, key.clone()).await;
+    if let Err(message) = check_limits(user_id, conversation.chat_id, types, DEFAULT_MAX_TOKENS) {
+        return format!("'{}'", message);
     }
-    add_new_messages(
+    let request = if types == MessageType::Image {
+        CompletionRequest::Image(ImageRequest {
+            model: "dall-e-3".to_string(),
+            prompt: prompt.clone(),
+        })
+    } else {
+        let old_messages = if is_follow || is_retry { followed_message } else { vec![] };
+        CompletionRequest::Chat(make_chat_request(conversation, user_id, old_messages, is_retry, prompt.clone()))
+    };
+    // the retried answer replaces the latest one
+    let replaces = latest_message.filter(|_| is_retry).map(|latest_message| {
+        format!(
+            "{:#?}-{}-{}",
+            latest_message.types, latest_message.question, latest_message.date
+        )
+    });
+    // only fresh questions are independent of the conversation, and
+    // incognito answers are not kept anywhere
+    let cache_key = match &request {
+        CompletionRequest::Chat(request) if !is_follow && !is_retry && !incognito && get_response_cache().enabled => {
+            Some(response_cache_key(request))
+        }
+        _ => None,
+    };
+    let mut pending = PendingCompletion {
         key,
+        conversation,
+        user_id,
         username,
         types,
         timestamp,
         prompt,
-        reply.clone(),
         is_follow,
+        replaces,
+        cache_key,
+        incognito,
+        request,
+    };
+    if let Some(cached) = cache_key.and_then(|cache_key| get_cached_response(&cache_key)) {
+        pending.cache_key = None;
+        let answer = Answer {
+            reply: cached.answer,
+            usage: Usage::default(),
+            model: cached.model,
+            cycles: 0,
+            used_tools: false,
+        };
+        return format!("'{}\n\n⚡ Cached answer'", store_completion(pending, answer));
+    }
+    // retried within the webhook call: an answer sent later, from outside
+    // the call, would go out once per replica
+    let policy = get_retry_policy();
+    let mut backoffs = policy.backoffs();
+    let result = loop {
+        match complete(&pending).await {
+            Err(err) if err.is_transient() => match backoffs.next() {
+                Some(backoff) => wait(backoff).await,
+                None => break Err(err),
+            },
+            result => break result,
+        }
+    };
+    match result {
+        Ok(answer) => format!("'{}'", store_completion(pending, answer)),
+        Err(err) => format!("'{}'", err.user_message()),
+    }
+}
+
+/// Waits at least `seconds` before the call goes on. A timer cannot resume
+/// the webhook call, which only its own callbacks may answer, so it waits on
+/// `raw_rand`, which takes a round each time.
+async fn wait(seconds: u64) {
+    let until = ic_cdk::api::time().saturating_add(seconds.saturating_mul(1_000_000_000));
+    while ic_cdk::api::time() < until {
+        if let Err((code, message)) = raw_rand().await {
+            ic_cdk::println!("raw_rand failed with code {:?}: {}", code, message);
+            return;
+        }
+    }
+}
+
+/// Enforces the bot's budget, the user's credits when completions are paid,
+/// the daily quotas of the user's tier and the request rate of the user and
+/// the chat. `max_tokens` is the longest answer the completion may get.
+/// Returns what to tell the user when over a limit.
+fn check_limits(user_id: i64, chat_id: i64, types: MessageType, max_tokens: u32) -> Result<(), String> {
+    check_budget(types)?;
+    check_credits(user_id, types, max_tokens)?;
+    let tier = get_user_tier(user_id);
+    if let Some(tier) = &tier {
+        let used = get_usage(UsageSubject::User, user_id, UsagePeriod::Day);
+        let (quota, used, what) = match types {
+            MessageType::Chat => (tier.daily_chats, used.requests, "chats"),
+            MessageType::Image => (tier.daily_images, used.images, "images"),
+        };
+        if let Some(quota) = quota.filter(|quota| used >= *quota) {
+            let minutes = until_next_day() / 60_000_000_000 + 1;
+            return Err(format!(
+                "You have used all {} {} of today. Your quota resets in {}h {}m, at 00:00 UTC.",
+                quota,
+                what,
+                minutes / 60,
+                minutes % 60
+            ));
+        }
+    }
+    take_rate_token(user_id, chat_id, tier.map(|tier| tier.bucket))
+        .map_err(|wait| format!("Slow down a little, please try again in {} seconds.", wait))
+}
+
+/// What the answer cost is attributed to the user and the chat, whether it
+/// succeeded or not.
+async fn complete(pending: &PendingCompletion) -> Result<Answer, LlmError> {
+    let mut cycles = 0;
+    let result = match &pending.request {
+        CompletionRequest::Image(request) => call_image(request, pending.key.clone(), &mut cycles)
+            .await
+            .map(|url| (url, Usage::default(), request.model.clone(), false)),
+        CompletionRequest::Chat(request) => {
+            let context = ToolContext {
+                user_id: pending.user_id,
+            };
+            chat_with_tools(request, pending.key.clone(), &context, &mut cycles)
+                .await
+                .map(|(completion, model, used_tools)| {
+                    let mut text = completion.text();
+                    if completion.is_truncated() {
+                        text.push_str("\n\n(The answer was cut off, press ➕ Continue for more.)");
+                    }
+                    (text, completion.usage, model, used_tools)
+                })
+        }
+    };
+    let usage = result.as_ref().ok().map(|(_, usage, _, _)| *usage);
+    record_usage(pending.user_id, pending.conversation.chat_id, pending.types, usage, cycles);
+    charge_credits(pending.user_id, cycles, usage);
+    result.map(|(reply, usage, model, used_tools)| Answer {
+        reply,
+        usage,
+        model,
+        cycles,
+        used_tools,
+    })
+}
+
+/// Stores the answer and returns it formatted for Telegram.
+fn store_completion(pending: PendingCompletion, answer: Answer) -> String {
+    let Answer {
+        reply,
+        usage,
+        model,
+        cycles,
+        used_tools,
+    } = answer;
+    if pending.incognito {
+        let message = Message {
+            conversation: pending.conversation,
+            user_id: pending.user_id,
+            username: pending.username,
+            date: pending.timestamp,
+            types: pending.types,
+            question: pending.prompt,
+            answer: reply.clone(),
+            is_follow: pending.is_follow,
+            usage,
+            model,
+        };
+        add_incognito_message(pending.conversation, pending.user_id, message, pending.replaces.is_some());
+        return convert_to_telegram_format(&reply, "html");
+    }
+    if let Some(replaces) = pending.replaces {
+        remove_message(replaces);
+    }
+    if let Some(cache_key) = pending.cache_key.filter(|_| !used_tools) {
+        put_cached_response(
+            cache_key,
+            CachedResponse {
+                answer: reply.clone(),
+                model: model.clone(),
+                created: ic_cdk::api::time(),
+                cycles,
+                user_id: Some(pending.user_id),
+            },
+        );
+    }
+    add_new_messages(
+        pending.key,
+        pending.conversation,
+        pending.user_id,
+        pending.username,
+        pending.types,
+        pending.timestamp,
+        pending.prompt,
+        reply.clone(),
+        pending.is_follow,
+        usage,
+        model,
     );
     ic_cdk::println!("before - {}", reply);
     let response = convert_to_telegram_format(&reply, "html");
@@ -122,10 +816,29 @@ pub async fn core_action(
     response
 }
 
-fn make_chat_request(old_messages: Vec<Message>, is_retry: bool, prompt: String) -> String {
+/// The current thread and its latest turn, from the in-memory buffer for
+/// incognito users.
+fn current_thread(conversation: Conversation, user_id: i64) -> (Vec<Message>, Option<Message>) {
+    if is_incognito(user_id) {
+        let messages = get_incognito_messages(conversation, user_id);
+        let latest = messages.last().cloned();
+        (messages, latest)
+    } else {
+        (get_followed_messages(conversation, user_id), get_latest_messages(conversation, user_id))
+    }
+}
+
+fn make_chat_request(
+    conversation: Conversation,
+    user_id: i64,
+    old_messages: Vec<Message>,
+    is_retry: bool,
+    prompt: String,
+) -> ChatRequest {
     let mut messages = vec![Form {
         role: "system".to_string(),
-        content: "You are a helpful assistant.".to_string(),
+        content: get_chat_prompt(conversation),
+        ..Default::default()
     }];
 
     old_messages
@@ -135,11 +848,13 @@ fn make_chat_request(old_messages: Vec<Message>, is_retry: bool, prompt: String)
             messages.push(Form {
                 role: "user".to_string(),
                 content: message.question.clone(),
+                ..Default::default()
             });
             if index < old_messages.len() - 1 || !is_retry {
                 messages.push(Form {
                     role: "assistant".to_string(),
                     content: message.answer.clone(),
+                    ..Default::default()
                 });
             }
         });
@@ -147,14 +862,59 @@ fn make_chat_request(old_messages: Vec<Message>, is_retry: bool, prompt: String)
         messages.push(Form {
             role: "user".to_string(),
             content: prompt,
+            ..Default::default()
         });
     }
 
-    json!({
-        "model": "gpt-4o",
-        "messages": messages
-    })
-    .to_string()
+    ChatRequest {
+        model: get_user_chat_model(conversation, user_id),
+        messages,
+        max_tokens: None,
+        tools: vec![],
+    }
+}
+
+/// Hashes the model, the system prompt and the normalized question.
+fn response_cache_key(request: &ChatRequest) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(request.model.as_bytes());
+    for message in &request.messages {
+        let content = if message.role == "user" {
+            message.content.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+        } else {
+            message.content.clone()
+        };
+        hasher.update([0]);
+        hasher.update(message.role.as_bytes());
+        hasher.update([0]);
+        hasher.update(content.as_bytes());
+    }
+    hasher.finalize().into()
+}
+
+fn make_inline_request(prompt: String) -> ChatRequest {
+    ChatRequest {
+        model: get_model(),
+        messages: vec![
+            Form {
+                role: "system".to_string(),
+                content: format!("{} Answer in at most three sentences.", get_prompt()),
+                ..Default::default()
+            },
+            Form {
+                role: "user".to_string(),
+                content: prompt,
+                ..Default::default()
+            },
+        ],
+        max_tokens: Some(INLINE_MAX_TOKENS),
+        tools: vec![],
+    }
+}
+
+/// Makes user text safe to send with HTML parse mode.
+pub fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;")
 }
 
 fn convert_to_telegram_format(input: &str, format_type: &str) -> String {
@@ -217,11 +977,42 @@ fn convert_to_telegram_format(input: &str, format_type: &str) -> String {
     formatted_text
 }
 
-fn send_message(chat: MessageChat, text: String) -> HttpResponse {
-    let mut m = SendMessage::new(chat, text);
+pub fn send_message(
+    chat: MessageChat,
+    thread_id: i64,
+    text: String,
+    keyboard: Option<InlineKeyboardMarkup>,
+) -> HttpResponse {
+    send_message_to(chat.id().into(), thread_id, text, keyboard)
+}
+
+/// Like `send_message`, for any chat, not only the one the update came from.
+pub fn send_message_to(
+    chat_id: i64,
+    thread_id: i64,
+    text: String,
+    keyboard: Option<InlineKeyboardMarkup>,
+) -> HttpResponse {
+    // notices queued by timers ride along with the reply
+    let mut notices = take_notices(chat_id);
+    notices.push(text);
+    let mut m = SendMessage::new(ChatId::new(chat_id), notices.join("\n\n"));
     m.parse_mode(ParseMode::Html);
+    if let Some(keyboard) = keyboard {
+        m.reply_markup(keyboard);
+    }
     let mut value = serde_json::to_value(m).unwrap();
-    add_method(&mut value, "sendMessage".to_string());
+    if thread_id != 0 {
+        // route the answer back into the forum topic
+        value["message_thread_id"] = json!(thread_id);
+    }
+    webhook_reply("sendMessage", value)
+}
+
+/// Calls a Bot API method by answering the webhook request, which saves an
+/// outcall.
+pub fn webhook_reply(method: &str, mut value: Value) -> HttpResponse {
+    add_method(&mut value, method.to_string());
     HttpResponse {
         status_code: 200,
         headers: vec![HeaderField(
@@ -233,11 +1024,61 @@ fn send_message(chat: MessageChat, text: String) -> HttpResponse {
     }
 }
 
+/// Uploads files as one album with `sendMediaGroup` by answering the
+/// webhook request, which takes a multipart body. Telegram wants two to ten
+/// files; the caption goes under the last one.
+pub fn documents_reply(chat_id: i64, documents: Vec<(&str, Vec<u8>)>, caption: &str) -> HttpResponse {
+    const BOUNDARY: &str = "----icp-gpt-bot-boundary";
+    let count = documents.len();
+    let media: Vec<Value> = (0..count)
+        .map(|index| {
+            let mut item = json!({ "type": "document", "media": format!("attach://file{}", index) });
+            if index + 1 == count {
+                item["caption"] = json!(caption);
+            }
+            item
+        })
+        .collect();
+    let mut body = vec![];
+    let fields = [
+        ("method", "sendMediaGroup".to_string()),
+        ("chat_id", chat_id.to_string()),
+        ("media", Value::Array(media).to_string()),
+    ];
+    for (name, value) in fields {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                BOUNDARY, name, value
+            )
+            .as_bytes(),
+        );
+    }
+    for (index, (filename, content)) in documents.into_iter().enumerate() {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"file{}\"; filename=\"{}\"\r\nContent-Type: application/octet-stream\r\n\r\n",
+                BOUNDARY, index, filename
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(&content);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+    HttpResponse {
+        status_code: 200,
+        headers: vec![HeaderField(
+            String::from("content-type"),
+            format!("multipart/form-data; boundary={}", BOUNDARY),
+        )],
+        body,
+        upgrade: Some(false),
+    }
+}
+
 fn add_method(value: &mut Value, method: String) {
-    match value {
-        Value::Object(m) => {
-            m.insert("method".to_string(), Value::String(method));
-        }
-        _ => (),
+    if let Value::Object(m) = value {
+        m.insert("method".to_string(), Value::String(method));
     }
 }
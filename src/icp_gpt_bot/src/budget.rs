@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use serde_json::json;
+
+use crate::memory::{get_balance_sample, get_budget, get_usage, set_balance_sample, until_next_day};
+use crate::telegram::call_telegram;
+use crate::types::{BalanceSample, MessageType, UsagePeriod, UsageSubject};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(10 * 60);
+const HOUR: u128 = 60 * 60 * 1_000_000_000;
+
+/// Refuses completions the bot cannot afford: images while the balance is
+/// below the reserve, and everything once the daily spend cap is reached.
+pub fn check_budget(types: MessageType) -> Result<(), String> {
+    let budget = get_budget();
+    if types == MessageType::Image && ic_cdk::api::canister_balance128() < budget.reserve_cycles {
+        return Err("Image generation is paused while the bot is low on cycles.".to_string());
+    }
+    let spent = get_usage(UsageSubject::Global, 0, UsagePeriod::Day).cycles;
+    if budget.daily_spend_cap.is_some_and(|cap| spent >= cap) {
+        let minutes = until_next_day() / 60_000_000_000 + 1;
+        return Err(format!(
+            "The bot has used up its budget for today. Please try again in {}h {}m.",
+            minutes / 60,
+            minutes % 60
+        ));
+    }
+    Ok(())
+}
+
+/// Timers do not survive upgrades, so this runs from `init` and
+/// `post_upgrade`.
+pub fn start_balance_watcher() {
+    ic_cdk_timers::set_timer_interval(CHECK_INTERVAL, || ic_cdk::spawn(watch_balance()));
+}
+
+/// Alerts the admin chat when the balance drops below a warning level or
+/// starts burning faster than allowed, once each time.
+async fn watch_balance() {
+    let budget = get_budget();
+    let balance = ic_cdk::api::canister_balance128();
+    let now = ic_cdk::api::time();
+    let previous = get_balance_sample();
+    // the most severe level the balance is below
+    let level = budget.warning_levels.iter().copied().filter(|level| balance < *level).min();
+    let burn_rate = if previous.time != 0 && previous.balance > balance {
+        (previous.balance - balance) * HOUR / (now - previous.time).max(1) as u128
+    } else {
+        0
+    };
+    let burning = budget.burn_rate_alert.is_some_and(|limit| burn_rate > limit);
+    set_balance_sample(BalanceSample {
+        balance,
+        time: now,
+        warned_level: level,
+        burning,
+    });
+
+    let mut alerts = vec![];
+    if let Some(level) = level.filter(|level| previous.warned_level.is_none_or(|warned| *level < warned)) {
+        alerts.push(format!(
+            "Cycle balance is down to {:.3}T, below the warning level of {:.3}T.",
+            balance as f64 / 1e12,
+            level as f64 / 1e12
+        ));
+    }
+    if burning && !previous.burning {
+        alerts.push(format!(
+            "The bot is burning {:.3}B cycles an hour.",
+            burn_rate as f64 / 1e9
+        ));
+    }
+
+    for alert in alerts {
+        ic_cdk::println!("Balance alert - {}", alert);
+        if let Some(chat_id) = budget.alert_chat_id {
+            let params = json!({ "chat_id": chat_id, "text": format!("⚠️ {}", alert) });
+            if let Err(err) = call_telegram("sendMessage", params).await {
+                ic_cdk::println!("sendMessage failed - {}", err);
+            }
+        }
+    }
+}
@@ -0,0 +1,424 @@
+use serde_json::json;
+use telegram_bot_raw::{MessageEntity, MessageEntityKind};
+
+use crate::telegram::call_telegram;
+
+/// Who may run a command.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Permission {
+    User,
+    ChatAdmin,
+    Moderator,
+    Admin,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CommandKind {
+    Start,
+    Help,
+    Retry,
+    Imagine,
+    Settings,
+    SetPrompt,
+    SetModel,
+    ResetSettings,
+    Usage,
+    Balance,
+    Topup,
+    Claim,
+    Credits,
+    Premium,
+    History,
+    Incognito,
+    Forget,
+    Export,
+    Ban,
+    Unban,
+    SetRole,
+    Roles,
+    Invite,
+    SetCommands,
+}
+
+pub struct Command {
+    pub kind: CommandKind,
+    pub name: &'static str,
+    pub aliases: &'static [&'static str],
+    /// Usage of the arguments, empty when the command takes none.
+    pub usage: &'static str,
+    pub description: &'static str,
+    pub permission: Permission,
+}
+
+/// Every command the bot understands. `/help` and `setMyCommands` are both
+/// generated from this table.
+pub const COMMANDS: &[Command] = &[
+    Command {
+        kind: CommandKind::Start,
+        name: "start",
+        aliases: &[],
+        usage: "",
+        description: "Say hello",
+        permission: Permission::User,
+    },
+    Command {
+        kind: CommandKind::Help,
+        name: "help",
+        aliases: &["commands"],
+        usage: "",
+        description: "Show this help",
+        permission: Permission::User,
+    },
+    Command {
+        kind: CommandKind::Retry,
+        name: "retry",
+        aliases: &["again"],
+        usage: "",
+        description: "Answer the last prompt again",
+        permission: Permission::User,
+    },
+    Command {
+        kind: CommandKind::Imagine,
+        name: "imagine",
+        aliases: &["image", "img"],
+        usage: "<prompt>",
+        description: "Generate an image",
+        permission: Permission::User,
+    },
+    Command {
+        kind: CommandKind::Usage,
+        name: "usage",
+        aliases: &[],
+        usage: "",
+        description: "Show what you have used today and this month; moderators can add a user id",
+        permission: Permission::User,
+    },
+    Command {
+        kind: CommandKind::Balance,
+        name: "balance",
+        aliases: &[],
+        usage: "<principal|account-id>",
+        description: "Show the balance and recent transactions of an account",
+        permission: Permission::User,
+    },
+    Command {
+        kind: CommandKind::Topup,
+        name: "topup",
+        aliases: &[],
+        usage: "",
+        description: "Show where to send ICP or ckBTC for credits",
+        permission: Permission::User,
+    },
+    Command {
+        kind: CommandKind::Claim,
+        name: "claim",
+        aliases: &[],
+        usage: "",
+        description: "Credit your deposit now",
+        permission: Permission::User,
+    },
+    Command {
+        kind: CommandKind::Credits,
+        name: "credits",
+        aliases: &[],
+        usage: "",
+        description: "Show your credits",
+        permission: Permission::User,
+    },
+    Command {
+        kind: CommandKind::Premium,
+        name: "premium",
+        aliases: &[],
+        usage: "",
+        description: "Get higher limits for Telegram Stars",
+        permission: Permission::User,
+    },
+    Command {
+        kind: CommandKind::History,
+        name: "history",
+        aliases: &[],
+        usage: "",
+        description: "Show what is stored about you in this chat",
+        permission: Permission::User,
+    },
+    Command {
+        kind: CommandKind::Incognito,
+        name: "incognito",
+        aliases: &[],
+        usage: "<on|off>",
+        description: "Stop storing your conversations",
+        permission: Permission::User,
+    },
+    Command {
+        kind: CommandKind::Forget,
+        name: "forget",
+        aliases: &[],
+        usage: "",
+        description: "Delete your conversations for good",
+        permission: Permission::User,
+    },
+    Command {
+        kind: CommandKind::Export,
+        name: "export",
+        aliases: &[],
+        usage: "",
+        description: "Get your data as JSON and Markdown files",
+        permission: Permission::User,
+    },
+    Command {
+        kind: CommandKind::Settings,
+        name: "settings",
+        aliases: &[],
+        usage: "",
+        description: "Show the settings of this chat",
+        permission: Permission::ChatAdmin,
+    },
+    Command {
+        kind: CommandKind::SetPrompt,
+        name: "setprompt",
+        aliases: &[],
+        usage: "<system prompt>",
+        description: "Set the system prompt of this chat",
+        permission: Permission::ChatAdmin,
+    },
+    Command {
+        kind: CommandKind::SetModel,
+        name: "setmodel",
+        aliases: &[],
+        usage: "<model>",
+        description: "Set the model of this chat",
+        permission: Permission::ChatAdmin,
+    },
+    Command {
+        kind: CommandKind::ResetSettings,
+        name: "resetsettings",
+        aliases: &[],
+        usage: "",
+        description: "Reset the settings of this chat",
+        permission: Permission::ChatAdmin,
+    },
+    Command {
+        kind: CommandKind::Ban,
+        name: "ban",
+        aliases: &[],
+        usage: "<user id>",
+        description: "Ignore a user from now on",
+        permission: Permission::Moderator,
+    },
+    Command {
+        kind: CommandKind::Unban,
+        name: "unban",
+        aliases: &[],
+        usage: "<user id>",
+        description: "Let a banned user in again",
+        permission: Permission::Moderator,
+    },
+    Command {
+        kind: CommandKind::SetRole,
+        name: "role",
+        aliases: &[],
+        usage: "<user id> <admin|moderator|premium|user|banned|none>",
+        description: "Give a user a role",
+        permission: Permission::Admin,
+    },
+    Command {
+        kind: CommandKind::Roles,
+        name: "roles",
+        aliases: &[],
+        usage: "",
+        description: "List users with a role",
+        permission: Permission::Admin,
+    },
+    Command {
+        kind: CommandKind::Invite,
+        name: "invite",
+        aliases: &[],
+        usage: "",
+        description: "Make a single-use invite link",
+        permission: Permission::Admin,
+    },
+    Command {
+        kind: CommandKind::SetCommands,
+        name: "setcommands",
+        aliases: &[],
+        usage: "",
+        description: "Register the command list with Telegram",
+        permission: Permission::Admin,
+    },
+];
+
+pub struct ParsedCommand {
+    pub command: &'static Command,
+    /// Everything after the command, trimmed.
+    pub argument: String,
+}
+
+impl ParsedCommand {
+    pub fn args(&self) -> Vec<&str> {
+        self.argument.split_whitespace().collect()
+    }
+}
+
+pub enum ParsedText {
+    /// A plain message, no command at its start.
+    Text,
+    Command(ParsedCommand),
+    /// A command we don't know, and whether it was addressed to us.
+    Unknown(String, bool),
+    /// A command addressed to another bot.
+    NotForUs,
+}
+
+pub fn find_command(name: &str) -> Option<&'static Command> {
+    let name = name.to_lowercase();
+    COMMANDS
+        .iter()
+        .find(|command| command.name == name || command.aliases.contains(&name.as_str()))
+}
+
+/// Commands are only recognized at the very start of a message, where
+/// Telegram marks them with a `bot_command` entity. While our own username
+/// is unknown, e.g. because `getMe` failed, `/cmd@anybot` is taken as ours.
+pub fn parse_command(text: &str, entities: &[MessageEntity], bot_username: &str) -> ParsedText {
+    let entity = entities
+        .iter()
+        .find(|entity| entity.offset == 0 && entity.kind == MessageEntityKind::BotCommand);
+    let length = match entity {
+        Some(entity) => utf16_to_byte_offset(text, entity.length as usize),
+        None => return ParsedText::Text,
+    };
+    let (token, rest) = text.split_at(length);
+    let token = token.trim_start_matches('/');
+    let (name, addressed) = match token.split_once('@') {
+        Some((name, target)) if bot_username.is_empty() || target.eq_ignore_ascii_case(bot_username) => (name, true),
+        Some(_) => return ParsedText::NotForUs,
+        None => (token, false),
+    };
+    match find_command(name) {
+        Some(command) => ParsedText::Command(ParsedCommand {
+            command,
+            argument: rest.trim().to_string(),
+        }),
+        None => ParsedText::Unknown(name.to_string(), addressed),
+    }
+}
+
+fn utf16_to_byte_offset(text: &str, utf16_offset: usize) -> usize {
+    let mut units = 0;
+    for (index, c) in text.char_indices() {
+        if units >= utf16_offset {
+            return index;
+        }
+        units += c.len_utf16();
+    }
+    text.len()
+}
+
+pub fn help_text(permission: Permission) -> String {
+    COMMANDS
+        .iter()
+        .filter(|command| command.permission <= permission)
+        .map(|command| {
+            let mut line = format!("/{}", command.name);
+            if !command.usage.is_empty() {
+                line = format!("{} {}", line, command.usage);
+            }
+            if !command.aliases.is_empty() {
+                let aliases: Vec<String> = command.aliases.iter().map(|alias| format!("/{}", alias)).collect();
+                line = format!("{} ({})", line, aliases.join(", "));
+            }
+            format!("{} - {}", line, command.description)
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Registers the command table with `setMyCommands`: user commands for
+/// everybody, chat admin commands for group administrators as well.
+pub async fn register_commands() -> Result<(), String> {
+    let commands = |permission: Permission| {
+        COMMANDS
+            .iter()
+            .filter(|command| command.permission <= permission)
+            .map(|command| json!({ "command": command.name, "description": command.description }))
+            .collect::<Vec<_>>()
+    };
+    call_telegram(
+        "setMyCommands",
+        json!({ "commands": commands(Permission::User) }),
+    )
+    .await?;
+    call_telegram(
+        "setMyCommands",
+        json!({
+            "commands": commands(Permission::ChatAdmin),
+            "scope": { "type": "all_chat_administrators" }
+        }),
+    )
+    .await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn command_entity(text: &str) -> Vec<MessageEntity> {
+        let command = text.split_whitespace().next().unwrap_or_default();
+        vec![MessageEntity {
+            offset: 0,
+            length: command.encode_utf16().count() as i64,
+            kind: MessageEntityKind::BotCommand,
+        }]
+    }
+
+    fn parse(text: &str, bot_username: &str) -> ParsedText {
+        parse_command(text, &command_entity(text), bot_username)
+    }
+
+    #[test]
+    fn commands_need_an_entity_at_the_start() {
+        assert!(matches!(parse_command("what is 1/2?", &[], "gpt_bot"), ParsedText::Text));
+        let entities = vec![MessageEntity {
+            offset: 5,
+            length: 5,
+            kind: MessageEntityKind::BotCommand,
+        }];
+        assert!(matches!(parse_command("see, /help", &entities, "gpt_bot"), ParsedText::Text));
+    }
+
+    #[test]
+    fn commands_are_found_by_name_and_alias() {
+        match parse("/IMG a red fox", "gpt_bot") {
+            ParsedText::Command(command) => {
+                assert_eq!(command.command.kind, CommandKind::Imagine);
+                assert_eq!(command.argument, "a red fox");
+                assert_eq!(command.args(), vec!["a", "red", "fox"]);
+            }
+            _ => panic!("expected /imagine"),
+        }
+        assert!(matches!(parse("/nope", "gpt_bot"), ParsedText::Unknown(name, false) if name == "nope"));
+    }
+
+    #[test]
+    fn commands_can_be_addressed() {
+        assert!(matches!(parse("/help@GPT_bot", "gpt_bot"), ParsedText::Command(_)));
+        assert!(matches!(parse("/help@other_bot", "gpt_bot"), ParsedText::NotForUs));
+        assert!(matches!(parse("/nope@gpt_bot", "gpt_bot"), ParsedText::Unknown(_, true)));
+        // our username is not known yet
+        assert!(matches!(parse("/help@gpt_bot", ""), ParsedText::Command(_)));
+    }
+
+    #[test]
+    fn arguments_after_wide_characters() {
+        assert_eq!(utf16_to_byte_offset("héllo", 2), 3);
+        // the emoji takes two UTF-16 units and four bytes
+        assert_eq!(utf16_to_byte_offset("😀 x", 2), 4);
+        assert_eq!(utf16_to_byte_offset("ab", 10), 2);
+        let text = "/imagine 🦊 in snow";
+        match parse(text, "gpt_bot") {
+            ParsedText::Command(command) => assert_eq!(command.argument, "🦊 in snow"),
+            _ => panic!("expected /imagine"),
+        }
+    }
+}
@@ -0,0 +1,185 @@
+use std::time::Duration;
+
+use sha2::{Digest, Sha256};
+
+use crate::cycles::{response_bytes_for_tokens, response_outcall_cycles};
+use crate::gpt::IMAGE_RESPONSE_BYTES;
+use crate::ledger::{account_identifier, balance_of, encode_account, format_amount, ledger_fee, transfer, Account};
+use crate::memory::{
+    add_credits, debit_credits, get_credit_config, get_credits, get_ledger, push_notice, watch_deposits,
+    watched_depositors,
+};
+use crate::types::{MessageType, Usage};
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How long after `/topup` deposits are picked up without `/claim`.
+const WATCH_DURATION: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Every user deposits to a subaccount of the canister derived from their
+/// Telegram id, so deposits can be told apart without memos.
+pub fn deposit_subaccount(user_id: i64) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"telegram-deposit");
+    hasher.update(user_id.to_be_bytes());
+    hasher.finalize().into()
+}
+
+fn deposit_account(user_id: i64) -> Account {
+    Account {
+        owner: ic_cdk::id(),
+        subaccount: Some(deposit_subaccount(user_id).to_vec()),
+    }
+}
+
+pub fn format_credits(credits: u128) -> String {
+    format!("{:.3}B credits", credits as f64 / 1e9)
+}
+
+/// Roughly what a completion of up to `max_tokens` tokens costs when the
+/// answer takes all of them; the prompt's tokens are not known yet.
+fn estimated_credits(types: MessageType, max_tokens: u32) -> u128 {
+    match types {
+        MessageType::Chat => {
+            response_outcall_cycles(response_bytes_for_tokens(max_tokens))
+                + max_tokens as u128 * get_credit_config().credits_per_llm_token
+        }
+        MessageType::Image => response_outcall_cycles(IMAGE_RESPONSE_BYTES),
+    }
+}
+
+/// Refuses completions when pay-per-use is on and the user's credits may
+/// not cover them.
+pub fn check_credits(user_id: i64, types: MessageType, max_tokens: u32) -> Result<(), String> {
+    if !get_credit_config().enabled {
+        return Ok(());
+    }
+    let credits = get_credits(user_id);
+    let needed = estimated_credits(types, max_tokens);
+    if credits < needed {
+        return Err(format!(
+            "You have {}, but this may cost up to {}. Send /topup to buy more.",
+            format_credits(credits),
+            format_credits(needed)
+        ));
+    }
+    Ok(())
+}
+
+/// Debits what a completion cost, failed attempts included.
+pub fn charge_credits(user_id: i64, cycles: u128, usage: Option<Usage>) {
+    let config = get_credit_config();
+    if !config.enabled {
+        return;
+    }
+    let tokens = usage.map(|usage| usage.prompt_tokens + usage.completion_tokens).unwrap_or_default();
+    debit_credits(user_id, cycles + tokens as u128 * config.credits_per_llm_token);
+}
+
+pub fn credits_text(user_id: i64) -> String {
+    format!("'You have {}.'", format_credits(get_credits(user_id)))
+}
+
+/// Shows where to send tokens and starts watching the deposit subaccount.
+pub fn topup_text(user_id: i64) -> String {
+    watch_deposits(user_id, ic_cdk::api::time() + WATCH_DURATION);
+    let account = deposit_account(user_id);
+    let icp_ledger = get_ledger().ledger_canister_id;
+    let mut lines = vec!["Send tokens to your deposit account:".to_string()];
+    for token in get_credit_config().tokens {
+        lines.push(format!(
+            "\n{} (1 {} buys {}):\n{}",
+            token.symbol,
+            token.symbol,
+            format_credits(token.credits_per_unit * 10u128.pow(token.decimals as u32)),
+            encode_account(&account)
+        ));
+        if token.ledger_canister_id == icp_ledger {
+            let subaccount = deposit_subaccount(user_id);
+            lines.push(format!("Account id: {}", account_identifier(&account.owner, Some(&subaccount))));
+        }
+    }
+    lines.push("\nDeposits minus the ledger fee are credited within a few minutes, or send /claim.".to_string());
+    format!("'{}'", lines.join("\n"))
+}
+
+/// Sweeps the user's deposit subaccount into the canister's main account
+/// for every payment token and credits what arrived. Returns the credits
+/// added.
+pub async fn claim_deposits(user_id: i64) -> Result<u128, String> {
+    let account = deposit_account(user_id);
+    let main = Account {
+        owner: ic_cdk::id(),
+        subaccount: None,
+    };
+    let mut credited = 0;
+    let mut errors = vec![];
+    for token in get_credit_config().tokens {
+        let result = async {
+            let balance = balance_of(token.ledger_canister_id, account.clone()).await?;
+            let fee = ledger_fee(token.ledger_canister_id).await?;
+            if balance <= fee {
+                return Ok(0);
+            }
+            let amount = balance - fee.clone();
+            let subaccount = deposit_subaccount(user_id);
+            let block = transfer(token.ledger_canister_id, subaccount, main.clone(), amount.clone(), fee).await?;
+            let credits = u128::try_from(&amount.0).unwrap_or(u128::MAX).saturating_mul(token.credits_per_unit);
+            add_credits(user_id, credits);
+            ic_cdk::println!(
+                "Deposit of {} {} by {} in block {}",
+                format_amount(&amount, token.decimals),
+                token.symbol,
+                user_id,
+                block
+            );
+            Ok::<u128, String>(credits)
+        }
+        .await;
+        match result {
+            Ok(credits) => credited += credits,
+            Err(err) => errors.push(format!("{}: {}", token.symbol, err)),
+        }
+    }
+    if credited == 0 && !errors.is_empty() {
+        return Err(errors.join("; "));
+    }
+    Ok(credited)
+}
+
+pub async fn claim_text(user_id: i64) -> String {
+    match claim_deposits(user_id).await {
+        Ok(0) => "'No new deposits yet. Transfers can take a few seconds to arrive.'".to_string(),
+        Ok(credited) => format!(
+            "'Added {}. You now have {}.'",
+            format_credits(credited),
+            format_credits(get_credits(user_id))
+        ),
+        Err(err) => format!("'Could not claim the deposit: {}'", err),
+    }
+}
+
+/// Timers do not survive upgrades, so this runs from `init` and
+/// `post_upgrade`.
+pub fn start_deposit_sweeper() {
+    ic_cdk_timers::set_timer_interval(SWEEP_INTERVAL, || ic_cdk::spawn(sweep_deposits()));
+}
+
+async fn sweep_deposits() {
+    for user_id in watched_depositors() {
+        match claim_deposits(user_id).await {
+            Ok(0) => {}
+            Ok(credited) => {
+                let text = format!(
+                    "Your deposit arrived: added {}. You now have {}.",
+                    format_credits(credited),
+                    format_credits(get_credits(user_id))
+                );
+                // the private chat with a user has the user's id; the notice
+                // comes with the next reply there
+                push_notice(user_id, text);
+            }
+            Err(err) => ic_cdk::println!("Deposit sweep for {} failed - {}", user_id, err),
+        }
+    }
+}
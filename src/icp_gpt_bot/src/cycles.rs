@@ -0,0 +1,64 @@
+use ic_cdk::api::call::{msg_cycles_refunded128, RejectionCode};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpResponse as HttpResponseCdk,
+};
+
+use crate::memory::record_outcall;
+
+/// Nodes in the subnet the canister runs on; 13 for application subnets.
+const SUBNET_SIZE: u128 = 13;
+
+/// Used by the replica when `max_response_bytes` is not set.
+const DEFAULT_MAX_RESPONSE_BYTES: u64 = 2_000_000;
+
+/// Room for everything around the generated text: ids, usage, headers.
+const RESPONSE_OVERHEAD_BYTES: u64 = 4_000;
+
+/// A token is about four characters, but non-ASCII text is escaped as
+/// `\uXXXX` in JSON, so leave plenty of room.
+const BYTES_PER_TOKEN: u64 = 16;
+
+/// What an HTTPS outcall costs, following the IC pricing:
+/// `(3M + 60K * n) * n` base fee, `400 * n` per request byte and `800 * n`
+/// per byte of the response limit, for a subnet of `n` nodes.
+pub fn outcall_cycles(request: &CanisterHttpRequestArgument) -> u128 {
+    let response_bytes = request.max_response_bytes.unwrap_or(DEFAULT_MAX_RESPONSE_BYTES);
+    response_outcall_cycles(response_bytes) + 400 * SUBNET_SIZE * request_bytes(request)
+}
+
+/// The part of the price that does not depend on the request, for
+/// estimates made before the request is built.
+pub fn response_outcall_cycles(max_response_bytes: u64) -> u128 {
+    (3_000_000 + 60_000 * SUBNET_SIZE) * SUBNET_SIZE + 800 * SUBNET_SIZE * max_response_bytes as u128
+}
+
+fn request_bytes(request: &CanisterHttpRequestArgument) -> u128 {
+    let headers: usize = request
+        .headers
+        .iter()
+        .map(|header| header.name.len() + header.value.len())
+        .sum();
+    let transform = request
+        .transform
+        .as_ref()
+        .map(|transform| transform.function.0.method.len() + transform.context.len())
+        .unwrap_or_default();
+    (request.url.len() + headers + request.body.as_ref().map(Vec::len).unwrap_or_default() + transform) as u128
+}
+
+/// The response limit for a completion of at most `max_tokens` tokens.
+pub fn response_bytes_for_tokens(max_tokens: u32) -> u64 {
+    RESPONSE_OVERHEAD_BYTES + BYTES_PER_TOKEN * max_tokens as u64
+}
+
+/// Sends an outcall with exactly the cycles it costs, and records what the
+/// replica refunded. Also returns the cycles actually spent.
+pub async fn outcall(
+    request: CanisterHttpRequestArgument,
+) -> (Result<HttpResponseCdk, (RejectionCode, String)>, u128) {
+    let cycles = outcall_cycles(&request);
+    let result = http_request(request, cycles).await;
+    let refunded = msg_cycles_refunded128();
+    record_outcall(cycles, refunded);
+    (result.map(|(response,)| response), cycles.saturating_sub(refunded))
+}
@@ -1,41 +1,546 @@
-use ic_cdk::api::management_canister::http_request::{
-    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod,
-    TransformContext as TransformContextCdk,
+use candid::Nat;
+use ic_cdk::api::management_canister::http_request::{CanisterHttpRequestArgument, HttpHeader, HttpMethod};
+use serde_json::{json, Value};
+
+use crate::cycles::{outcall, outcall_cycles, response_bytes_for_tokens};
+use crate::memory::{
+    api_key_count, current_api_key, get_backend, get_fallback_chain, redact_api_keys, rotate_api_key,
 };
-use serde_json::json;
+use crate::transform::TransformKind;
+use crate::types::{BackendConfig, BackendKind, Form, ToolCall, Usage};
 
+pub const PROXY_URL: &str = "https://us-central1-telegram-gpt-488cd.cloudfunctions.net/chatgpt";
 
-pub async fn call_chatgpt(uri: &str, reqeust_body: String, key: String) -> String {
-    let url = format!("https://us-central1-telegram-gpt-488cd.cloudfunctions.net/chatgpt/{}", uri);
-    
-    let body = json!({
-        "request": reqeust_body,
-        "key": key
-    }).to_string();
+/// Completion limit when a request sets none.
+pub const DEFAULT_MAX_TOKENS: u32 = 4096;
 
-    let request = CanisterHttpRequestArgument {
-        url,
-        method: HttpMethod::POST,
-        headers: vec![HttpHeader {
-            name: "Content-Type".to_string(),
-            value: "application/json".to_string(),
-        }],
-        body: Some(body.as_bytes().to_vec()),
-        max_response_bytes: Some(50_000), // 3MB
-        transform: Some(TransformContextCdk::from_name(
-            "transform".to_string(),
-            vec![],
-        )),
-    };
+/// An image answer is only a URL and a revised prompt.
+pub const IMAGE_RESPONSE_BYTES: u64 = 10_000;
+
+/// Sent to providers that take a seed, along with temperature 0, so that
+/// every replica gets the same answer as far as the provider allows.
+const SEED: u64 = 0;
+
+#[derive(Clone)]
+pub struct ChatRequest {
+    pub model: String,
+    pub messages: Vec<Form>,
+    pub max_tokens: Option<u32>,
+    /// Function specs offered to the model. Only OpenAI style backends
+    /// send them.
+    pub tools: Vec<Value>,
+}
+
+impl ChatRequest {
+    pub fn max_tokens(&self) -> u32 {
+        self.max_tokens.unwrap_or(DEFAULT_MAX_TOKENS)
+    }
+
+    /// Whether the conversation holds tool calls or their results, which
+    /// only OpenAI style backends understand.
+    pub fn has_tool_turns(&self) -> bool {
+        self.messages
+            .iter()
+            .any(|message| message.tool_calls.is_some() || message.tool_call_id.is_some())
+    }
+}
+
+#[derive(Clone)]
+pub struct ImageRequest {
+    pub model: String,
+    pub prompt: String,
+}
+
+/// A successful chat completion.
+pub struct Completion {
+    pub content: String,
+    pub finish_reason: Option<String>,
+    /// Set when the model declined to answer.
+    pub refusal: Option<String>,
+    pub usage: Usage,
+    /// Tools the model wants called before it answers.
+    pub tool_calls: Vec<ToolCall>,
+}
+
+impl Completion {
+    /// What to show the user: the refusal if there is one, else the content.
+    pub fn text(&self) -> String {
+        self.refusal.clone().unwrap_or_else(|| self.content.clone())
+    }
+
+    /// Whether the answer stopped at the token limit.
+    pub fn is_truncated(&self) -> bool {
+        matches!(self.finish_reason.as_deref(), Some("length") | Some("max_tokens"))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum LlmError {
+    RateLimited,
+    ContextTooLong,
+    ContentFiltered,
+    /// The outcall failed or the provider answered with an error.
+    HttpFailure(String),
+    /// Not enough cycles left to pay for the outcall.
+    CyclesExhausted,
+    /// The Cloud Function proxy did not answer properly.
+    ProxyDown(String),
+    /// The backend cannot do what was asked, e.g. generate images.
+    Unsupported,
+}
+
+impl LlmError {
+    /// Errors that may go away when the same request is sent again later.
+    pub fn is_transient(&self) -> bool {
+        matches!(
+            self,
+            LlmError::RateLimited | LlmError::HttpFailure(_) | LlmError::ProxyDown(_)
+        )
+    }
+
+    pub fn user_message(&self) -> String {
+        match self {
+            LlmError::RateLimited => "The AI provider is busy right now. Please try again in a minute.",
+            LlmError::ContextTooLong => "This conversation is too long. Press 🆕 New chat or send a prompt without + to start over.",
+            LlmError::ContentFiltered => "Your request was blocked by the content filter.",
+            LlmError::HttpFailure(_) => "Could not reach the AI provider. Please try again later.",
+            LlmError::CyclesExhausted => "The bot is out of cycles right now. Please try again later.",
+            LlmError::ProxyDown(_) => "The AI service is unavailable right now. Please try again later.",
+            LlmError::Unsupported => "Image generation is not supported by this backend.",
+        }
+        .to_string()
+    }
+}
+
+/// A provider of chat completions. Implementations only translate requests
+/// and responses; the outcall itself is shared. `request_id` identifies the
+/// request towards the proxy.
+pub trait LlmBackend {
+    fn chat_request(&self, request: &ChatRequest, request_id: &str) -> CanisterHttpRequestArgument;
 
-    let cycles = 700_000_000;
+    fn parse_chat_response(&self, status: u16, body: &[u8]) -> Result<Completion, LlmError>;
+
+    /// `None` when the provider cannot generate images.
+    fn image_request(&self, request: &ImageRequest, request_id: &str) -> Option<CanisterHttpRequestArgument>;
+
+    /// Returns the URL of the generated image.
+    fn parse_image_response(&self, status: u16, body: &[u8]) -> Result<String, LlmError>;
+}
+
+/// The Cloud Function proxy, which forwards OpenAI requests wrapped as
+/// `{ request, key }` to `/chat` and `/image`.
+pub struct ProxyBackend {
+    pub config: BackendConfig,
+}
+
+/// Any server speaking OpenAI's `/v1/chat/completions`.
+///
+/// Every replica sends the request, and providers do not deduplicate them,
+/// so direct backends are meant to sit behind a proxy that does, keyed by
+/// the `Idempotency-Key` header. Called directly, each question is billed
+/// once per replica and the replicas may disagree on the answer.
+pub struct OpenAiBackend {
+    pub config: BackendConfig,
+    pub api_key: Option<String>,
+}
+
+/// Anthropic-style `/v1/messages`. Like `OpenAiBackend`, meant to sit
+/// behind a deduplicating proxy.
+pub struct AnthropicBackend {
+    pub config: BackendConfig,
+    pub api_key: Option<String>,
+}
+
+/// Lets a proxy in front of the provider send the request of all replicas
+/// only once.
+fn idempotency_header(request_id: &str) -> HttpHeader {
+    HttpHeader {
+        name: "Idempotency-Key".to_string(),
+        value: request_id.to_string(),
+    }
+}
+
+impl OpenAiBackend {
+    fn headers(&self, request_id: &str) -> Vec<HttpHeader> {
+        let mut headers = vec![idempotency_header(request_id)];
+        if let Some(api_key) = &self.api_key {
+            headers.push(HttpHeader {
+                name: "Authorization".to_string(),
+                value: format!("Bearer {}", api_key),
+            });
+        }
+        headers
+    }
+}
+
+impl AnthropicBackend {
+    fn headers(&self, request_id: &str) -> Vec<HttpHeader> {
+        let mut headers = vec![idempotency_header(request_id)];
+        if let Some(api_key) = &self.api_key {
+            headers.push(HttpHeader {
+                name: "x-api-key".to_string(),
+                value: api_key.clone(),
+            });
+        }
+        if !self
+            .config
+            .headers
+            .iter()
+            .any(|header| header.0.eq_ignore_ascii_case("anthropic-version"))
+        {
+            headers.push(HttpHeader {
+                name: "anthropic-version".to_string(),
+                value: "2023-06-01".to_string(),
+            });
+        }
+        headers
+    }
+}
+
+impl LlmBackend for ProxyBackend {
+    fn chat_request(&self, request: &ChatRequest, request_id: &str) -> CanisterHttpRequestArgument {
+        let body = json!({
+            "request": openai_chat_body(request).to_string(),
+            "key": request_id
+        });
+        let url = format!("{}/chat", self.config.base_url);
+        let response_bytes = response_bytes_for_tokens(request.max_tokens());
+        post(&self.config, url, vec![], body, response_bytes, TransformKind::Raw)
+    }
+
+    fn parse_chat_response(&self, status: u16, body: &[u8]) -> Result<Completion, LlmError> {
+        parse_proxy_response(status, body).map(|content| Completion {
+            content,
+            finish_reason: None,
+            refusal: None,
+            usage: Usage::default(),
+            tool_calls: vec![],
+        })
+    }
+
+    fn image_request(&self, request: &ImageRequest, request_id: &str) -> Option<CanisterHttpRequestArgument> {
+        let body = json!({
+            "request": openai_image_body(request).to_string(),
+            "key": request_id
+        });
+        let url = format!("{}/image", self.config.base_url);
+        Some(post(&self.config, url, vec![], body, IMAGE_RESPONSE_BYTES, TransformKind::Raw))
+    }
+
+    fn parse_image_response(&self, status: u16, body: &[u8]) -> Result<String, LlmError> {
+        parse_proxy_response(status, body)
+    }
+}
 
-    match http_request(request, cycles).await {
-        Ok((response,)) => String::from_utf8(response.body)
-            .unwrap_or_else(|_| "Failed to parse response".to_string()),
+impl LlmBackend for OpenAiBackend {
+    fn chat_request(&self, request: &ChatRequest, request_id: &str) -> CanisterHttpRequestArgument {
+        let url = format!("{}/v1/chat/completions", self.config.base_url);
+        let mut body = openai_chat_body(request);
+        body["temperature"] = json!(0);
+        body["seed"] = json!(SEED);
+        if !request.tools.is_empty() {
+            body["tools"] = json!(request.tools);
+        }
+        post(
+            &self.config,
+            url,
+            self.headers(request_id),
+            body,
+            response_bytes_for_tokens(request.max_tokens()),
+            TransformKind::OpenAiChat,
+        )
+    }
+
+    fn parse_chat_response(&self, status: u16, body: &[u8]) -> Result<Completion, LlmError> {
+        let value = parse_json(status, body)?;
+        let choice = &value["choices"][0];
+        let finish_reason = choice["finish_reason"].as_str().map(str::to_string);
+        if finish_reason.as_deref() == Some("content_filter") {
+            return Err(LlmError::ContentFiltered);
+        }
+        Ok(Completion {
+            content: choice["message"]["content"].as_str().unwrap_or_default().to_string(),
+            finish_reason,
+            refusal: choice["message"]["refusal"].as_str().map(str::to_string),
+            usage: Usage {
+                prompt_tokens: value["usage"]["prompt_tokens"].as_u64().unwrap_or_default(),
+                completion_tokens: value["usage"]["completion_tokens"].as_u64().unwrap_or_default(),
+            },
+            tool_calls: serde_json::from_value(choice["message"]["tool_calls"].clone()).unwrap_or_default(),
+        })
+    }
+
+    fn image_request(&self, request: &ImageRequest, request_id: &str) -> Option<CanisterHttpRequestArgument> {
+        let url = format!("{}/v1/images/generations", self.config.base_url);
+        Some(post(
+            &self.config,
+            url,
+            self.headers(request_id),
+            openai_image_body(request),
+            IMAGE_RESPONSE_BYTES,
+            TransformKind::OpenAiImage,
+        ))
+    }
+
+    fn parse_image_response(&self, status: u16, body: &[u8]) -> Result<String, LlmError> {
+        let value = parse_json(status, body)?;
+        value["data"][0]["url"]
+            .as_str()
+            .map(str::to_string)
+            .ok_or_else(|| LlmError::HttpFailure("Missing image url".to_string()))
+    }
+}
+
+impl LlmBackend for AnthropicBackend {
+    fn chat_request(&self, request: &ChatRequest, request_id: &str) -> CanisterHttpRequestArgument {
+        let system: Vec<&str> = request
+            .messages
+            .iter()
+            .filter(|message| message.role == "system")
+            .map(|message| message.content.as_str())
+            .collect();
+        let messages: Vec<&Form> = request
+            .messages
+            .iter()
+            .filter(|message| message.role != "system")
+            .collect();
+        let body = json!({
+            "model": request.model,
+            "system": system.join("\n"),
+            "messages": messages,
+            "max_tokens": request.max_tokens(),
+            // Anthropic takes no seed
+            "temperature": 0,
+        });
+        let url = format!("{}/v1/messages", self.config.base_url);
+        let response_bytes = response_bytes_for_tokens(request.max_tokens());
+        post(&self.config, url, self.headers(request_id), body, response_bytes, TransformKind::AnthropicMessages)
+    }
+
+    fn parse_chat_response(&self, status: u16, body: &[u8]) -> Result<Completion, LlmError> {
+        let value = parse_json(status, body)?;
+        let finish_reason = value["stop_reason"].as_str().map(str::to_string);
+        let content: Vec<&str> = value["content"]
+            .as_array()
+            .map(|content| content.iter().filter_map(|block| block["text"].as_str()).collect())
+            .unwrap_or_default();
+        Ok(Completion {
+            content: content.join(""),
+            refusal: if finish_reason.as_deref() == Some("refusal") {
+                Some("I can't help with that.".to_string())
+            } else {
+                None
+            },
+            finish_reason,
+            usage: Usage {
+                prompt_tokens: value["usage"]["input_tokens"].as_u64().unwrap_or_default(),
+                completion_tokens: value["usage"]["output_tokens"].as_u64().unwrap_or_default(),
+            },
+            tool_calls: vec![],
+        })
+    }
+
+    fn image_request(&self, _request: &ImageRequest, _request_id: &str) -> Option<CanisterHttpRequestArgument> {
+        None
+    }
+
+    fn parse_image_response(&self, _status: u16, _body: &[u8]) -> Result<String, LlmError> {
+        Err(LlmError::Unsupported)
+    }
+}
+
+/// The backend for a configuration, using the current API key of its
+/// provider.
+pub fn backend_for(config: BackendConfig) -> Box<dyn LlmBackend> {
+    let api_key = current_api_key(&config.base_url);
+    match config.kind {
+        BackendKind::Proxy => Box::new(ProxyBackend { config }),
+        BackendKind::OpenAi => Box::new(OpenAiBackend { config, api_key }),
+        BackendKind::Anthropic => Box::new(AnthropicBackend { config, api_key }),
+    }
+}
+
+/// `cycles` is increased by what every outcall made on the way costs, so
+/// failed attempts are paid for too.
+pub async fn call_chatgpt(
+    request: &ChatRequest,
+    request_id: String,
+    cycles: &mut u128,
+) -> Result<Completion, LlmError> {
+    call_backend(get_backend(), request, request_id, cycles).await
+}
+
+/// Tries the primary backend, then every step of the fallback chain, until
+/// one answers. Steps on backends that cannot read tool turns are skipped
+/// once the model called tools. Returns the completion and the model that
+/// produced it.
+pub async fn call_with_fallback(
+    request: &ChatRequest,
+    request_id: String,
+    cycles: &mut u128,
+) -> Result<(Completion, String), LlmError> {
+    let mut result = call_chatgpt(request, request_id.clone(), cycles)
+        .await
+        .map(|completion| (completion, request.model.clone()));
+    for step in get_fallback_chain() {
+        match &result {
+            Err(err) if err.is_transient() || *err == LlmError::ContextTooLong => {}
+            _ => break,
+        }
+        let config = step.backend.unwrap_or_else(get_backend);
+        if request.has_tool_turns() && config.kind != BackendKind::OpenAi {
+            continue;
+        }
+        let request = ChatRequest {
+            model: step.model.clone(),
+            ..request.clone()
+        };
+        result = call_backend(config, &request, request_id.clone(), cycles)
+            .await
+            .map(|completion| (completion, step.model));
+    }
+    result
+}
+
+/// Sends the request with each API key in turn while the provider answers
+/// `429 Too Many Requests`.
+async fn call_backend(
+    config: BackendConfig,
+    request: &ChatRequest,
+    request_id: String,
+    cycles: &mut u128,
+) -> Result<Completion, LlmError> {
+    for _ in 0..api_key_count(&config.base_url).max(1) {
+        let backend = backend_for(config.clone());
+        let (status, body) = send(backend.chat_request(request, &request_id), cycles).await?;
+        match backend.parse_chat_response(status, &body) {
+            Err(LlmError::RateLimited) => rotate_api_key(&config.base_url),
+            result => return result.map_err(log_error),
+        }
+    }
+    Err(log_error(LlmError::RateLimited))
+}
+
+pub async fn call_image(
+    request: &ImageRequest,
+    request_id: String,
+    cycles: &mut u128,
+) -> Result<String, LlmError> {
+    let config = get_backend();
+    for _ in 0..api_key_count(&config.base_url).max(1) {
+        let backend = backend_for(config.clone());
+        let http_request = backend.image_request(request, &request_id).ok_or(LlmError::Unsupported)?;
+        let (status, body) = send(http_request, cycles).await?;
+        match backend.parse_image_response(status, &body) {
+            Err(LlmError::RateLimited) => rotate_api_key(&config.base_url),
+            result => return result.map_err(log_error),
+        }
+    }
+    Err(log_error(LlmError::RateLimited))
+}
+
+fn log_error(err: LlmError) -> LlmError {
+    ic_cdk::println!("LLM request failed - {}", redact_api_keys(&format!("{:?}", err)));
+    err
+}
+
+async fn send(request: CanisterHttpRequestArgument, cycles: &mut u128) -> Result<(u16, Vec<u8>), LlmError> {
+    if ic_cdk::api::canister_balance128() < outcall_cycles(&request) {
+        return Err(log_error(LlmError::CyclesExhausted));
+    }
+
+    let (result, spent) = outcall(request).await;
+    *cycles += spent;
+    match result {
+        Ok(response) => Ok((status_code(&response.status), response.body)),
         Err((r, m)) => {
-            format!("HTTP request failed with code {:?}: {}", r, m)
+            let message = redact_api_keys(&format!("HTTP request failed with code {:?}: {}", r, m));
+            if m.contains("cycles") {
+                Err(log_error(LlmError::CyclesExhausted))
+            } else {
+                Err(log_error(LlmError::HttpFailure(message)))
+            }
         }
     }
 }
 
+fn status_code(status: &Nat) -> u16 {
+    u16::try_from(&status.0).unwrap_or(500)
+}
+
+fn post(
+    config: &BackendConfig,
+    url: String,
+    mut headers: Vec<HttpHeader>,
+    body: Value,
+    max_response_bytes: u64,
+    transform: TransformKind,
+) -> CanisterHttpRequestArgument {
+    headers.push(HttpHeader {
+        name: "Content-Type".to_string(),
+        value: "application/json".to_string(),
+    });
+    config.headers.iter().for_each(|header| {
+        headers.push(HttpHeader {
+            name: header.0.clone(),
+            value: header.1.clone(),
+        })
+    });
+    CanisterHttpRequestArgument {
+        url,
+        method: HttpMethod::POST,
+        headers,
+        body: Some(body.to_string().into_bytes()),
+        max_response_bytes: Some(max_response_bytes),
+        transform: Some(transform.context()),
+    }
+}
+
+fn openai_chat_body(request: &ChatRequest) -> Value {
+    json!({
+        "model": request.model,
+        "messages": request.messages,
+        "max_tokens": request.max_tokens(),
+    })
+}
+
+fn openai_image_body(request: &ImageRequest) -> Value {
+    json!({
+        "model": request.model,
+        "prompt": request.prompt,
+        "n": 1,
+    })
+}
+
+/// The proxy answers with a JSON string, or with plain text such as
+/// `Rate exceeded.` when something went wrong.
+fn parse_proxy_response(status: u16, body: &[u8]) -> Result<String, LlmError> {
+    let text = String::from_utf8_lossy(body).to_string();
+    if status == 429 || text == "Rate exceeded." {
+        return Err(LlmError::RateLimited);
+    }
+    match serde_json::from_slice::<String>(body) {
+        Ok(content) if status < 400 => Ok(content),
+        _ if status < 400 => Ok(text),
+        _ => Err(LlmError::ProxyDown(redact_api_keys(&text))),
+    }
+}
+
+/// Parses a provider's JSON body and maps its error responses.
+fn parse_json(status: u16, body: &[u8]) -> Result<Value, LlmError> {
+    let value = serde_json::from_slice::<Value>(body);
+    let error = match &value {
+        Ok(value) if status < 400 && value["error"].is_null() => return Ok(value.clone()),
+        Ok(value) => value["error"].clone(),
+        Err(_) => Value::Null,
+    };
+    let code = error["code"].as_str().or(error["type"].as_str()).unwrap_or_default();
+    let message = error["message"].as_str().unwrap_or_default().to_string();
+    Err(match (status, code) {
+        (429, _) | (_, "rate_limit_exceeded") | (_, "rate_limit_error") => LlmError::RateLimited,
+        (_, "context_length_exceeded") => LlmError::ContextTooLong,
+        (_, "content_policy_violation") | (_, "content_filter") => LlmError::ContentFiltered,
+        _ if message.contains("prompt is too long") => LlmError::ContextTooLong,
+        _ => LlmError::HttpFailure(redact_api_keys(&format!("{} {}", status, message))),
+    })
+}
@@ -0,0 +1,636 @@
+use candid::{CandidType, Nat, Principal};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use sha2::{Digest, Sha224};
+
+use crate::memory::{format_time, get_ledger};
+use crate::types::IndexKind;
+
+/// An account as typed by a user: an ICRC-1 owner or a legacy ICP
+/// account identifier (64 hex characters).
+pub enum AccountRef {
+    Principal(Principal),
+    AccountId(String),
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize)]
+struct Tokens {
+    e8s: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct TimeStamp {
+    timestamp_nanos: u64,
+}
+
+#[derive(CandidType)]
+struct GetAccountIdentifierTransactionsArgs {
+    account_identifier: String,
+    start: Option<u64>,
+    max_results: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+enum GetAccountIdentifierTransactionsResult {
+    Ok(GetAccountIdentifierTransactionsResponse),
+    Err(IndexError),
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetAccountIdentifierTransactionsResponse {
+    balance: u64,
+    transactions: Vec<TransactionWithId>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct IndexError {
+    message: String,
+}
+
+#[derive(CandidType, Deserialize)]
+struct TransactionWithId {
+    id: u64,
+    transaction: Transaction,
+}
+
+#[derive(CandidType, Deserialize)]
+struct Transaction {
+    operation: Operation,
+    timestamp: Option<TimeStamp>,
+}
+
+#[derive(CandidType, Deserialize)]
+enum Operation {
+    Transfer { from: String, to: String, amount: Tokens },
+    Mint { to: String, amount: Tokens },
+    Burn { from: String, amount: Tokens },
+    Approve { from: String, spender: String },
+    /// A transfer by `spender` out of an allowance `from` gave them.
+    TransferFrom { from: String, to: String, spender: String, amount: Tokens },
+}
+
+#[derive(CandidType)]
+struct GetAccountTransactionsArgs {
+    account: Account,
+    start: Option<Nat>,
+    max_results: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+enum GetAccountTransactionsResult {
+    Ok(GetAccountTransactionsResponse),
+    Err(IndexError),
+}
+
+#[derive(CandidType, Deserialize)]
+struct GetAccountTransactionsResponse {
+    balance: Nat,
+    transactions: Vec<IcrcTransactionWithId>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct IcrcTransactionWithId {
+    id: Nat,
+    transaction: IcrcTransaction,
+}
+
+/// An ICRC-1 index transaction; one of the operations is set.
+#[derive(CandidType, Deserialize)]
+struct IcrcTransaction {
+    transfer: Option<IcrcTransfer>,
+    mint: Option<IcrcMint>,
+    burn: Option<IcrcBurn>,
+    approve: Option<IcrcApprove>,
+    timestamp: u64,
+}
+
+#[derive(CandidType, Deserialize)]
+struct IcrcTransfer {
+    from: Account,
+    to: Account,
+    amount: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+struct IcrcMint {
+    amount: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+struct IcrcBurn {
+    amount: Nat,
+}
+
+#[derive(CandidType, Deserialize)]
+struct IcrcApprove {
+    spender: Account,
+}
+
+/// What `/balance` and the ledger tool show.
+pub struct AccountSummary {
+    pub account: String,
+    pub balance: Nat,
+    pub symbol: String,
+    pub decimals: u8,
+    pub transactions: Vec<TransactionSummary>,
+    /// Why the transactions could not be looked up, if they could not.
+    pub history_error: Option<String>,
+}
+
+pub struct TransactionSummary {
+    pub id: u64,
+    /// `in`, `out` or `approve`, seen from the account.
+    pub direction: &'static str,
+    pub amount: Nat,
+    pub counterparty: Option<String>,
+    pub timestamp: Option<u64>,
+}
+
+pub fn parse_account(text: &str) -> Result<AccountRef, String> {
+    let text = text.trim();
+    if text.len() == 64 && text.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Ok(AccountRef::AccountId(text.to_lowercase()));
+    }
+    Principal::from_text(text)
+        .map(AccountRef::Principal)
+        .map_err(|_| "That is neither a principal nor an account id.".to_string())
+}
+
+/// The ICP account identifier of a subaccount of `owner`, the default one
+/// when `None`: a CRC32 checksum followed by
+/// `sha224("\x0Aaccount-id" || owner || subaccount)`.
+pub fn account_identifier(owner: &Principal, subaccount: Option<&[u8; 32]>) -> String {
+    let mut hasher = Sha224::new();
+    hasher.update(b"\x0Aaccount-id");
+    hasher.update(owner.as_slice());
+    hasher.update(subaccount.unwrap_or(&[0u8; 32]));
+    let hash = hasher.finalize();
+    let checksum = crc32fast::hash(&hash);
+    format!("{}{}", hex::encode(checksum.to_be_bytes()), hex::encode(hash))
+}
+
+/// Looks up the balance on the ledger and, if an index canister is
+/// configured, the latest transactions. When the index fails, the balance
+/// is shown without them.
+pub async fn lookup_account(account: AccountRef, max_transactions: u64) -> Result<AccountSummary, String> {
+    let config = get_ledger();
+    let balance = match &account {
+        AccountRef::Principal(owner) => {
+            let arg = Account {
+                owner: *owner,
+                subaccount: None,
+            };
+            Some(balance_of(config.ledger_canister_id, arg).await?)
+        }
+        AccountRef::AccountId(_) => None,
+    };
+
+    let history = match (config.index_canister_id, config.index_kind, &account) {
+        (None, _, _) => Ok((None, vec![])),
+        (Some(index), IndexKind::Icp, account) => {
+            let account_id = match account {
+                AccountRef::Principal(owner) => account_identifier(owner, None),
+                AccountRef::AccountId(account_id) => account_id.clone(),
+            };
+            icp_index_transactions(index, account_id, max_transactions).await
+        }
+        (Some(index), IndexKind::Icrc, AccountRef::Principal(owner)) => {
+            let account = Account {
+                owner: *owner,
+                subaccount: None,
+            };
+            icrc_index_transactions(index, account, max_transactions).await
+        }
+        (Some(_), IndexKind::Icrc, AccountRef::AccountId(_)) => {
+            Err("The index of this ledger only knows principals, not account ids.".to_string())
+        }
+    };
+    let (index_balance, transactions, history_error) = match history {
+        Ok((index_balance, transactions)) => (index_balance, transactions, None),
+        Err(err) => {
+            ic_cdk::println!("Index lookup failed - {}", err);
+            (None, vec![], Some(err))
+        }
+    };
+    let balance = match balance.or(index_balance) {
+        Some(balance) => balance,
+        None => {
+            return Err(history_error
+                .unwrap_or_else(|| "Account ids can only be looked up through an index canister.".to_string()))
+        }
+    };
+
+    Ok(AccountSummary {
+        account: match account {
+            AccountRef::Principal(owner) => owner.to_text(),
+            AccountRef::AccountId(account_id) => account_id,
+        },
+        balance,
+        symbol: config.symbol,
+        decimals: config.decimals,
+        transactions,
+        history_error,
+    })
+}
+
+/// The balance and latest transactions from the ICP index.
+async fn icp_index_transactions(
+    index: Principal,
+    account_id: String,
+    max_transactions: u64,
+) -> Result<(Option<Nat>, Vec<TransactionSummary>), String> {
+    let arg = GetAccountIdentifierTransactionsArgs {
+        account_identifier: account_id.clone(),
+        start: None,
+        max_results: max_transactions,
+    };
+    let (result,): (GetAccountIdentifierTransactionsResult,) =
+        ic_cdk::call(index, "get_account_identifier_transactions", (arg,))
+            .await
+            .map_err(|(code, message)| format!("Index call failed with code {:?}: {}", code, message))?;
+    let response = match result {
+        GetAccountIdentifierTransactionsResult::Ok(response) => response,
+        GetAccountIdentifierTransactionsResult::Err(err) => return Err(err.message),
+    };
+    let transactions = response
+        .transactions
+        .into_iter()
+        .map(|transaction| summarize(transaction, &account_id))
+        .collect();
+    Ok((Some(Nat::from(response.balance)), transactions))
+}
+
+/// The balance and latest transactions from an ICRC-1 index.
+async fn icrc_index_transactions(
+    index: Principal,
+    account: Account,
+    max_transactions: u64,
+) -> Result<(Option<Nat>, Vec<TransactionSummary>), String> {
+    let arg = GetAccountTransactionsArgs {
+        account: account.clone(),
+        start: None,
+        max_results: Nat::from(max_transactions),
+    };
+    let (result,): (GetAccountTransactionsResult,) = ic_cdk::call(index, "get_account_transactions", (arg,))
+        .await
+        .map_err(|(code, message)| format!("Index call failed with code {:?}: {}", code, message))?;
+    let response = match result {
+        GetAccountTransactionsResult::Ok(response) => response,
+        GetAccountTransactionsResult::Err(err) => return Err(err.message),
+    };
+    let transactions = response
+        .transactions
+        .into_iter()
+        .map(|transaction| summarize_icrc(transaction, &account))
+        .collect();
+    Ok((Some(response.balance), transactions))
+}
+
+/// The ICRC-1 textual form of an account:
+/// `<owner>-<checksum>.<subaccount in hex without leading zeros>`.
+pub fn encode_account(account: &Account) -> String {
+    let subaccount = match &account.subaccount {
+        Some(subaccount) if subaccount.iter().any(|byte| *byte != 0) => subaccount,
+        _ => return account.owner.to_text(),
+    };
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(account.owner.as_slice());
+    hasher.update(subaccount);
+    let checksum = base32(&hasher.finalize().to_be_bytes());
+    let subaccount = hex::encode(subaccount);
+    format!("{}-{}.{}", account.owner.to_text(), checksum, subaccount.trim_start_matches('0'))
+}
+
+/// RFC 4648 base32, lowercase and without padding.
+fn base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut text = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            text.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        text.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    text
+}
+
+pub async fn balance_of(ledger: Principal, account: Account) -> Result<Nat, String> {
+    let (balance,): (Nat,) = ic_cdk::call(ledger, "icrc1_balance_of", (account,))
+        .await
+        .map_err(|(code, message)| format!("Ledger call failed with code {:?}: {}", code, message))?;
+    Ok(balance)
+}
+
+pub async fn ledger_fee(ledger: Principal) -> Result<Nat, String> {
+    let (fee,): (Nat,) = ic_cdk::call(ledger, "icrc1_fee", ())
+        .await
+        .map_err(|(code, message)| format!("Ledger call failed with code {:?}: {}", code, message))?;
+    Ok(fee)
+}
+
+/// Moves `amount` out of one of the canister's own subaccounts and returns
+/// the block index.
+pub async fn transfer(
+    ledger: Principal,
+    from_subaccount: [u8; 32],
+    to: Account,
+    amount: Nat,
+    fee: Nat,
+) -> Result<Nat, String> {
+    let arg = TransferArg {
+        from_subaccount: Some(from_subaccount.to_vec()),
+        to,
+        amount,
+        fee: Some(fee),
+        memo: None,
+        created_at_time: None,
+    };
+    let (result,): (Result<Nat, TransferError>,) = ic_cdk::call(ledger, "icrc1_transfer", (arg,))
+        .await
+        .map_err(|(code, message)| format!("Ledger call failed with code {:?}: {}", code, message))?;
+    result.map_err(|err| format!("Transfer failed: {:?}", err))
+}
+
+fn summarize(transaction: TransactionWithId, account_id: &str) -> TransactionSummary {
+    let (direction, amount, counterparty) = match transaction.transaction.operation {
+        Operation::Transfer { from, to, amount } | Operation::TransferFrom { from, to, amount, .. }
+            if from == account_id =>
+        {
+            ("out", amount.e8s, Some(to))
+        }
+        Operation::Transfer { from, amount, .. } | Operation::TransferFrom { from, amount, .. } => {
+            ("in", amount.e8s, Some(from))
+        }
+        Operation::Mint { amount, .. } => ("in", amount.e8s, None),
+        Operation::Burn { amount, .. } => ("out", amount.e8s, None),
+        Operation::Approve { spender, .. } => ("approve", 0, Some(spender)),
+    };
+    TransactionSummary {
+        id: transaction.id,
+        direction,
+        amount: Nat::from(amount),
+        counterparty,
+        timestamp: transaction.transaction.timestamp.map(|timestamp| timestamp.timestamp_nanos),
+    }
+}
+
+fn summarize_icrc(transaction: IcrcTransactionWithId, account: &Account) -> TransactionSummary {
+    let IcrcTransaction {
+        transfer,
+        mint,
+        burn,
+        approve,
+        timestamp,
+    } = transaction.transaction;
+    let account = encode_account(account);
+    let (direction, amount, counterparty) = match (transfer, mint, burn, approve) {
+        (Some(transfer), _, _, _) if encode_account(&transfer.from) == account => {
+            ("out", transfer.amount, Some(encode_account(&transfer.to)))
+        }
+        (Some(transfer), _, _, _) => ("in", transfer.amount, Some(encode_account(&transfer.from))),
+        (_, Some(mint), _, _) => ("in", mint.amount, None),
+        (_, _, Some(burn), _) => ("out", burn.amount, None),
+        (_, _, _, Some(approve)) => ("approve", Nat::from(0u64), Some(encode_account(&approve.spender))),
+        _ => ("unknown", Nat::from(0u64), None),
+    };
+    TransactionSummary {
+        id: u64::try_from(&transaction.id.0).unwrap_or(u64::MAX),
+        direction,
+        amount,
+        counterparty,
+        timestamp: Some(timestamp),
+    }
+}
+
+/// `123456789` with 8 decimals is `1.23456789`.
+pub fn format_amount(amount: &Nat, decimals: u8) -> String {
+    let digits = amount.0.to_string();
+    let decimals = decimals as usize;
+    if decimals == 0 {
+        return digits;
+    }
+    let digits = format!("{:0>width$}", digits, width = decimals + 1);
+    let (whole, fraction) = digits.split_at(digits.len() - decimals);
+    let fraction = fraction.trim_end_matches('0');
+    if fraction.is_empty() {
+        whole.to_string()
+    } else {
+        format!("{}.{}", whole, fraction)
+    }
+}
+
+impl AccountSummary {
+    pub fn to_text(&self) -> String {
+        let mut lines = vec![
+            format!("Account: {}", self.account),
+            format!("Balance: {} {}", format_amount(&self.balance, self.decimals), self.symbol),
+        ];
+        if !self.transactions.is_empty() {
+            lines.push("\nRecent transactions:".to_string());
+        }
+        for transaction in &self.transactions {
+            let mut line = format!(
+                "#{} {} {} {}",
+                transaction.id,
+                transaction.direction,
+                format_amount(&transaction.amount, self.decimals),
+                self.symbol
+            );
+            if let Some(counterparty) = &transaction.counterparty {
+                let preposition = if transaction.direction == "out" { "to" } else { "from" };
+                line = format!("{} {} {}", line, preposition, counterparty);
+            }
+            if let Some(timestamp) = transaction.timestamp {
+                line = format!("{}, {}", line, format_time(timestamp));
+            }
+            lines.push(line);
+        }
+        if self.history_error.is_some() {
+            lines.push("\nRecent transactions are unavailable right now.".to_string());
+        }
+        lines.join("\n")
+    }
+
+    pub fn to_json(&self) -> Value {
+        let transactions: Vec<Value> = self
+            .transactions
+            .iter()
+            .map(|transaction| {
+                json!({
+                    "id": transaction.id,
+                    "direction": transaction.direction,
+                    "amount": format_amount(&transaction.amount, self.decimals),
+                    "counterparty": transaction.counterparty,
+                    "time": transaction.timestamp.map(format_time),
+                })
+            })
+            .collect();
+        json!({
+            "account": self.account,
+            "balance": format_amount(&self.balance, self.decimals),
+            "symbol": self.symbol,
+            "recent_transactions": transactions,
+            "history_unavailable": self.history_error,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use candid::{Decode, Encode};
+
+    const OWNER: &str = "k2t6j-2nvnp-4zjm3-25dtz-6xhaa-c7boj-5gayf-oj3xs-i43lp-teztq-6ae";
+
+    #[test]
+    fn account_identifier_of_the_anonymous_principal() {
+        assert_eq!(
+            account_identifier(&Principal::anonymous(), None),
+            "1c7a48ba6a562aa9eaa2481a9049cdf0433b9738c992d698c31d8abf89cadc79"
+        );
+        assert_eq!(
+            account_identifier(&Principal::anonymous(), Some(&[0; 32])),
+            account_identifier(&Principal::anonymous(), None)
+        );
+    }
+
+    #[test]
+    fn accounts_are_encoded_as_in_icrc1() {
+        let owner = Principal::from_text(OWNER).unwrap();
+        let account = |subaccount: Option<Vec<u8>>| encode_account(&Account { owner, subaccount });
+        assert_eq!(account(None), OWNER);
+        assert_eq!(account(Some(vec![0; 32])), OWNER);
+        let mut one = vec![0; 32];
+        one[31] = 1;
+        assert_eq!(account(Some(one)), format!("{}-6cc627i.1", OWNER));
+        assert_eq!(
+            account(Some((1..=32).collect())),
+            format!("{}-dfxgiyy.102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f20", OWNER)
+        );
+    }
+
+    #[test]
+    fn amounts_are_formatted_with_decimals() {
+        assert_eq!(format_amount(&Nat::from(123_456_789u64), 8), "1.23456789");
+        assert_eq!(format_amount(&Nat::from(100_000_000u64), 8), "1");
+        assert_eq!(format_amount(&Nat::from(10_000u64), 8), "0.0001");
+        assert_eq!(format_amount(&Nat::from(0u64), 8), "0");
+        assert_eq!(format_amount(&Nat::from(42u64), 0), "42");
+    }
+
+    #[test]
+    fn transfers_from_an_allowance_are_summarized() {
+        let transaction = |operation| TransactionWithId {
+            id: 7,
+            transaction: Transaction {
+                operation,
+                timestamp: None,
+            },
+        };
+        let transfer_from = || Operation::TransferFrom {
+            from: "alice".to_string(),
+            to: "bob".to_string(),
+            spender: "carol".to_string(),
+            amount: Tokens { e8s: 5 },
+        };
+        let sent = summarize(transaction(transfer_from()), "alice");
+        assert_eq!(
+            (sent.direction, sent.amount, sent.counterparty.as_deref()),
+            ("out", Nat::from(5u64), Some("bob"))
+        );
+        let received = summarize(transaction(transfer_from()), "bob");
+        assert_eq!(received.direction, "in");
+        assert_eq!(received.counterparty.as_deref(), Some("alice"));
+        // the variant decodes when the index sends it
+        let bytes = Encode!(&transfer_from()).unwrap();
+        assert!(matches!(Decode!(&bytes, Operation).unwrap(), Operation::TransferFrom { .. }));
+    }
+
+    #[test]
+    fn icrc_transactions_are_summarized() {
+        // the index sends more fields than are read
+        #[derive(CandidType)]
+        struct FullTransfer {
+            from: Account,
+            to: Account,
+            amount: Nat,
+            fee: Option<Nat>,
+            memo: Option<Vec<u8>>,
+        }
+        #[derive(CandidType)]
+        struct FullTransaction {
+            kind: String,
+            transfer: Option<FullTransfer>,
+            mint: Option<()>,
+            burn: Option<()>,
+            approve: Option<()>,
+            timestamp: u64,
+        }
+        let owner = Principal::from_text(OWNER).unwrap();
+        let alice = Account { owner, subaccount: None };
+        let bob = Account {
+            owner: Principal::anonymous(),
+            subaccount: Some(vec![0; 32]),
+        };
+        let bytes = Encode!(&FullTransaction {
+            kind: "transfer".to_string(),
+            transfer: Some(FullTransfer {
+                from: alice.clone(),
+                to: bob.clone(),
+                amount: Nat::from(2_500u64),
+                fee: Some(Nat::from(10u64)),
+                memo: None,
+            }),
+            mint: None,
+            burn: None,
+            approve: None,
+            timestamp: 1_700_000_000_000_000_000,
+        })
+        .unwrap();
+        let transaction = || IcrcTransactionWithId {
+            id: Nat::from(9u64),
+            transaction: Decode!(&bytes, IcrcTransaction).unwrap(),
+        };
+        let sent = summarize_icrc(transaction(), &alice);
+        assert_eq!((sent.id, sent.direction, sent.amount), (9, "out", Nat::from(2_500u64)));
+        assert_eq!(sent.counterparty, Some(Principal::anonymous().to_text()));
+        // a zero subaccount is the default one
+        let received = summarize_icrc(transaction(), &Account { subaccount: None, ..bob });
+        assert_eq!(received.direction, "in");
+        assert_eq!(received.counterparty.as_deref(), Some(OWNER));
+    }
+}
@@ -1,37 +1,357 @@
 mod types;
+mod access;
 mod bot;
+mod budget;
+mod commands;
+mod credits;
+mod cycles;
 mod gpt;
+mod ledger;
 mod memory;
+mod premium;
+mod privacy;
+mod retention;
+mod telegram;
+mod tools;
+mod transform;
 
-use bot::handle_message;
-use types::{HttpRequest, HttpResponse, HeaderField, InitArg};
+use access::handle_unknown_user;
+use bot::{handle_callback, handle_inline_query, handle_message};
+use budget::start_balance_watcher;
+use credits::start_deposit_sweeper;
+use retention::start_retention_sweeper;
+use premium::{answer_pre_checkout, handle_refunded_payment, handle_successful_payment, refund_payment};
+use telegram::rotate_webhook;
+use transform::transform_response;
+use types::{BackendConfig, BotConfig, BudgetConfig, CanisterStats, CreditConfig, FallbackStep, HttpRequest, HttpResponse, HeaderField, InitArg, LedgerConfig, MessageExtras, OutcallStats, PremiumConfig, RateLimits, ResponseCacheConfig, ResponseCacheStats, RetentionConfig, RetentionStats, RetryPolicy, Role, Message, RoleChange, StarPayment, UsagePeriod, UserRole, UserSummary, UserUsage, UpdateExtras};
 use telegram_bot_raw::{MessageKind, Update, UpdateKind};
 use ic_cdk::{
-    api::management_canister::http_request::{HttpResponse as HttpResponseCdk, TransformArgs as TransformArgsCdk}, query, update, init
+    api::management_canister::http_request::{HttpResponse as HttpResponseCdk, TransformArgs as TransformArgsCdk}, query, update, init, post_upgrade
 };
-use crate::memory::{PROMPT_STORE, ADMIN_STORE, TOKEN_STORE, USERNAME_STORE};
-
-// #[init]
-// fn init(arg: InitArg) {
-//     ADMIN_STORE.with(|admin_store| {
-//         *admin_store.borrow_mut() = arg.admin;
-//     });
-//     TOKEN_STORE.with(|token_store| {
-//         *token_store.borrow_mut() = arg.token;
-//     });
-//     USERNAME_STORE.with(|username_store| {
-//         let mut binding = username_store.borrow_mut();
-//         for username in arg.usernames {
-//             binding.push(username);
-//         };
-//     });
-//     PROMPT_STORE.with(|prompt_store| {
-//         let mut binding = prompt_store.borrow_mut();
-//         for prompt in arg.prompts {
-//             binding.insert(prompt.shortcut, prompt.prompt);
-//         }
-//     })
-// }
+use crate::memory::{api_key_count, add_credits, get_retention, get_retention_stats, set_retention, clear_history, get_bot_config, list_users, message_counts, set_bot_config, user_history, get_backend, get_budget, get_credit_config, get_credits, get_fallback_chain, get_ledger, get_outcall_stats, get_premium_config, get_rate_limits, get_response_cache, get_retry_policy, is_token_valid, tools_enabled, response_cache_stats, star_payments, top_spenders, get_role, is_allowed, list_roles, role_audit_log, set_role, set_api_keys, set_backend, set_budget, set_credit_config, set_fallback_chain, set_ledger, set_premium_config, set_rate_limits, set_response_cache, set_retry_policy, set_tools_enabled, set_user_tier, set_admin, set_token, set_usernames, add_shortcuts};
+
+const MAX_RETRY_WAIT_SECS: u64 = 30;
+
+#[init]
+fn init(arg: Option<InitArg>) {
+    start_balance_watcher();
+    start_deposit_sweeper();
+    start_retention_sweeper();
+    let Some(arg) = arg else {
+        return;
+    };
+    set_admin(arg.admin);
+    set_token(arg.token);
+    set_usernames(arg.usernames);
+    add_shortcuts(arg.prompts);
+}
+
+#[post_upgrade]
+fn post_upgrade() {
+    start_balance_watcher();
+    start_deposit_sweeper();
+    start_retention_sweeper();
+}
+
+fn is_controller() -> Result<(), String> {
+    if ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        Ok(())
+    } else {
+        Err("Only controllers can call this method.".to_string())
+    }
+}
+
+/// Every replica sends each completion request. The default proxy answers
+/// them all from one call; `OpenAi` and `Anthropic` backends should point at
+/// a proxy that deduplicates requests by their `Idempotency-Key` header,
+/// or each question is paid for once per replica.
+#[update(guard = "is_controller")]
+fn set_llm_backend(config: BackendConfig) {
+    set_backend(config);
+}
+
+/// Header values may hold credentials, so only their names are returned.
+#[query(guard = "is_controller")]
+fn get_llm_backend() -> BackendConfig {
+    let mut config = get_backend();
+    config.headers.iter_mut().for_each(|header| header.1 = "***".to_string());
+    config
+}
+
+/// Replaces the API keys used for direct calls to the provider at
+/// `base_url`, the primary backend's or a fallback step's. Keys are rotated
+/// round-robin when the provider rate limits us, and are never returned.
+#[update(guard = "is_controller")]
+fn set_llm_api_keys(base_url: String, keys: Vec<String>) {
+    set_api_keys(base_url, keys);
+}
+
+#[query(guard = "is_controller")]
+fn get_llm_api_key_count(base_url: String) -> u64 {
+    api_key_count(&base_url) as u64
+}
+
+/// Models tried in order when the primary one fails with a transient error
+/// or a too long context.
+#[update(guard = "is_controller")]
+fn set_llm_fallback_chain(chain: Vec<FallbackStep>) {
+    set_fallback_chain(chain);
+}
+
+#[query(guard = "is_controller")]
+fn get_llm_fallback_chain() -> Vec<FallbackStep> {
+    let mut chain = get_fallback_chain();
+    chain
+        .iter_mut()
+        .filter_map(|step| step.backend.as_mut())
+        .for_each(|config| config.headers.iter_mut().for_each(|header| header.1 = "***".to_string()));
+    chain
+}
+
+/// The webhook call waits for the retries, and Telegram gives up on it
+/// after a minute, so the waits may add up to `MAX_RETRY_WAIT_SECS`.
+#[update(guard = "is_controller")]
+fn set_llm_retry_policy(policy: RetryPolicy) -> Result<(), String> {
+    if policy.max_attempts == 0 {
+        return Err("max_attempts must be at least 1.".to_string());
+    }
+    if policy.backoffs().fold(0u64, u64::saturating_add) > MAX_RETRY_WAIT_SECS {
+        return Err(format!("The waits between attempts may add up to {} seconds.", MAX_RETRY_WAIT_SECS));
+    }
+    set_retry_policy(policy);
+    Ok(())
+}
+
+#[query(guard = "is_controller")]
+fn get_llm_retry_policy() -> RetryPolicy {
+    get_retry_policy()
+}
+
+/// Cycles attached to outcalls and refunded by the replica, to see what
+/// the bot actually spends.
+#[query(guard = "is_controller")]
+fn get_outcall_cycles() -> OutcallStats {
+    get_outcall_stats()
+}
+
+/// Users ordered by the cycles they spent in the current day or month.
+#[query(guard = "is_controller")]
+fn get_top_spenders(period: UsagePeriod, limit: u32) -> Vec<UserUsage> {
+    top_spenders(period, limit as usize)
+}
+
+#[update(guard = "is_controller")]
+fn set_limits(limits: RateLimits) {
+    set_rate_limits(limits);
+}
+
+#[query(guard = "is_controller")]
+fn get_limits() -> RateLimits {
+    get_rate_limits()
+}
+
+/// Moves a user to another tier, or back to the default one with `null`.
+#[update(guard = "is_controller")]
+fn set_limit_tier(user_id: i64, tier: Option<String>) -> Result<(), String> {
+    if let Some(name) = &tier {
+        if !get_rate_limits().tiers.iter().any(|tier| &tier.name == name) {
+            return Err(format!("Unknown tier {}.", name));
+        }
+    }
+    set_user_tier(user_id, tier);
+    Ok(())
+}
+
+#[update(guard = "is_controller")]
+fn set_cycle_budget(budget: BudgetConfig) {
+    set_budget(budget);
+}
+
+#[query(guard = "is_controller")]
+fn get_cycle_budget() -> BudgetConfig {
+    get_budget()
+}
+
+/// The cache only answers fresh questions in chats, never follow-ups.
+#[update(guard = "is_controller")]
+fn set_response_cache_config(config: ResponseCacheConfig) {
+    set_response_cache(config);
+}
+
+#[query(guard = "is_controller")]
+fn get_response_cache_config() -> ResponseCacheConfig {
+    get_response_cache()
+}
+
+#[query(guard = "is_controller")]
+fn get_response_cache_stats() -> ResponseCacheStats {
+    response_cache_stats()
+}
+
+/// Lets OpenAI style backends call the built-in tools while answering.
+#[update(guard = "is_controller")]
+fn set_llm_tools_enabled(enabled: bool) {
+    set_tools_enabled(enabled);
+}
+
+#[query(guard = "is_controller")]
+fn get_llm_tools_enabled() -> bool {
+    tools_enabled()
+}
+
+/// The ledger and index canister used by `/balance` and the ledger tool.
+#[update(guard = "is_controller")]
+fn set_ledger_config(config: LedgerConfig) {
+    set_ledger(config);
+}
+
+#[query(guard = "is_controller")]
+fn get_ledger_config() -> LedgerConfig {
+    get_ledger()
+}
+
+/// Payment tokens, their prices in credits and whether completions are
+/// paid with credits at all.
+#[update(guard = "is_controller")]
+fn set_credits_config(config: CreditConfig) {
+    set_credit_config(config);
+}
+
+#[query(guard = "is_controller")]
+fn get_credits_config() -> CreditConfig {
+    get_credit_config()
+}
+
+#[query(guard = "is_controller")]
+fn get_user_credits(user_id: i64) -> u128 {
+    get_credits(user_id)
+}
+
+/// Grants credits, e.g. as a refund. Returns the new balance.
+#[update(guard = "is_controller")]
+fn grant_user_credits(user_id: i64, credits: u128) -> u128 {
+    add_credits(user_id, credits)
+}
+
+/// What `/premium` sells for Telegram Stars.
+#[update(guard = "is_controller")]
+fn set_premium(config: PremiumConfig) -> Result<(), String> {
+    if !get_rate_limits().tiers.iter().any(|tier| tier.name == config.tier) {
+        return Err(format!("Unknown tier {}.", config.tier));
+    }
+    set_premium_config(config);
+    Ok(())
+}
+
+#[query(guard = "is_controller")]
+fn get_premium() -> PremiumConfig {
+    get_premium_config()
+}
+
+/// Stars receipts of one user, or of everyone with `null`.
+#[query(guard = "is_controller")]
+fn get_star_payments(user_id: Option<i64>) -> Vec<StarPayment> {
+    star_payments(user_id)
+}
+
+#[update(guard = "is_controller")]
+async fn refund_star_payment(user_id: i64, charge_id: String) -> Result<StarPayment, String> {
+    refund_payment(user_id, charge_id).await
+}
+
+/// Gives a Telegram user a role, or takes it away with `null`.
+#[update(guard = "is_controller")]
+fn set_user_role(user_id: i64, role: Option<Role>) {
+    set_role(user_id, role, format!("principal:{}", ic_cdk::caller()));
+}
+
+#[query(guard = "is_controller")]
+fn get_user_roles() -> Vec<UserRole> {
+    list_roles()
+}
+
+/// The latest `limit` role changes, oldest first.
+#[query(guard = "is_controller")]
+fn get_role_audit_log(limit: u64) -> Vec<RoleChange> {
+    role_audit_log(limit)
+}
+
+/// Model, prompt, admin username, username whitelist and shortcuts.
+#[query(guard = "is_controller")]
+fn get_config() -> BotConfig {
+    get_bot_config()
+}
+
+#[update(guard = "is_controller")]
+fn set_config(config: BotConfig) {
+    set_bot_config(config);
+}
+
+/// Users with stored messages, the most recently active first.
+#[query(guard = "is_controller")]
+fn get_users() -> Vec<UserSummary> {
+    list_users()
+}
+
+/// The user's latest `limit` messages, oldest first.
+#[query(guard = "is_controller")]
+fn get_user_history(user_id: i64, limit: u32) -> Vec<Message> {
+    user_history(user_id, limit as usize)
+}
+
+/// Deletes one user's messages, or all messages with `null`. Returns how
+/// many were deleted.
+#[update(guard = "is_controller")]
+fn clear_user_history(user_id: Option<i64>) -> u64 {
+    clear_history(user_id)
+}
+
+/// Moves the webhook to a new random path and returns its URL.
+#[update(guard = "is_controller")]
+async fn rotate_webhook_token() -> Result<String, String> {
+    rotate_webhook().await
+}
+
+#[query(guard = "is_controller")]
+fn get_canister_stats() -> CanisterStats {
+    let (users, conversations, messages) = message_counts();
+    CanisterStats {
+        cycles: ic_cdk::api::canister_balance128(),
+        heap_bytes: heap_size(),
+        stable_bytes: ic_cdk::api::stable::stable_size() * 65_536,
+        users,
+        conversations,
+        messages,
+        outcalls: get_outcall_stats(),
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn heap_size() -> u64 {
+    core::arch::wasm32::memory_size(0) as u64 * 65_536
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn heap_size() -> u64 {
+    0
+}
+
+/// How long messages are kept; a background sweep enforces it.
+#[update(guard = "is_controller")]
+fn set_retention_policy(config: RetentionConfig) {
+    set_retention(config);
+    start_retention_sweeper();
+}
+
+#[query(guard = "is_controller")]
+fn get_retention_policy() -> RetentionConfig {
+    get_retention()
+}
+
+#[query(guard = "is_controller")]
+fn get_retention_sweeps() -> RetentionStats {
+    get_retention_stats()
+}
 
 #[update]
 async fn http_request_update(req: HttpRequest) -> HttpResponse {
@@ -50,12 +370,7 @@ async fn http_request(_req: HttpRequest) -> HttpResponse {
 
 #[query]
 fn transform(raw: TransformArgsCdk) -> HttpResponseCdk {
-    HttpResponseCdk {
-        status: raw.response.status.clone(),
-        body: raw.response.body.clone(),
-        headers: vec![],
-        ..Default::default()
-    }
+    transform_response(raw)
 }
 
 pub async fn handle_http_request(req: HttpRequest) -> HttpResponse {
@@ -72,7 +387,15 @@ pub async fn handle_http_request(req: HttpRequest) -> HttpResponse {
     }
 }
 
-async fn handle_telegram(_token: &str, req: HttpRequest) -> HttpResponse {
+async fn handle_telegram(token: &str, req: HttpRequest) -> HttpResponse {
+    if !is_token_valid(token.to_string()) {
+        return err404(req);
+    }
+    let mut extras = serde_json::from_slice::<UpdateExtras>(&req.body).unwrap_or_default();
+    // not an update kind `telegram_bot_raw` can parse
+    if let Some(query) = extras.pre_checkout_query.take() {
+        return answer_pre_checkout(query);
+    }
     match serde_json::from_slice::<Update>(&req.body) {
         Err(err) => HttpResponse {
             status_code: 500,
@@ -82,9 +405,55 @@ async fn handle_telegram(_token: &str, req: HttpRequest) -> HttpResponse {
         },
         Ok(update) => match update.kind {
             UpdateKind::Message(msg) => match msg.kind {
-                MessageKind::Text { data, .. } => handle_message(msg.from.username.unwrap(), msg.chat, data).await,
-                _ => ok200(),
+                MessageKind::Text { ref data, ref entities } => {
+                    let (text, entities) = (data.clone(), entities.clone());
+                    // banned users are silently ignored, unknown ones may ask for access
+                    match get_role(msg.from.id.into(), &msg.from.username.clone().unwrap_or_default()) {
+                        Some(Role::Banned) => ok200(),
+                        Some(_) => {
+                            let thread_id = extras.message.map(|message| message.topic_id()).unwrap_or_default();
+                            handle_message(msg, text, entities, thread_id).await.unwrap_or_else(ok200)
+                        }
+                        None => handle_unknown_user(msg, text).await.unwrap_or_else(ok200),
+                    }
+                }
+                _ => {
+                    let thread_id = extras.message.as_ref().map(MessageExtras::topic_id).unwrap_or_default();
+                    match extras.message {
+                        Some(MessageExtras {
+                            successful_payment: Some(payment),
+                            ..
+                        }) => handle_successful_payment(msg, payment, thread_id),
+                        Some(MessageExtras {
+                            refunded_payment: Some(refund),
+                            ..
+                        }) => {
+                            handle_refunded_payment(refund);
+                            ok200()
+                        }
+                        _ => ok200(),
+                    }
+                }
             },
+            UpdateKind::CallbackQuery(query) => {
+                let role = get_role(query.from.id.into(), &query.from.username.clone().unwrap_or_default());
+                if role == Some(Role::Banned) {
+                    return ok200();
+                }
+                let thread_id = extras
+                    .callback_query
+                    .and_then(|query| query.message)
+                    .map(|message| message.topic_id())
+                    .unwrap_or_default();
+                handle_callback(query, thread_id).await.unwrap_or_else(ok200)
+            }
+            UpdateKind::InlineQuery(query) => {
+                if is_allowed(query.from.id.into(), &query.from.username.clone().unwrap_or_default()) {
+                    handle_inline_query(query).await
+                } else {
+                    ok200()
+                }
+            }
             _ => ok200(),
         },
     }
@@ -1,144 +1,1643 @@
-use std::cell::{Cell, RefCell};
-use std::collections::BTreeMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Bound;
 
-use crate::types::{Message, MessageType};
+use candid::Principal;
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::{DefaultMemoryImpl, StableBTreeMap, StableCell, Storable};
+
+use crate::gpt::PROXY_URL;
+use crate::types::{
+    AccessRequest, ApiKeys, BackendConfig, BalanceSample, Config, BackendKind, BotConfig, BucketConfig, BudgetConfig, CachedResponse, ChatSettings, CreditConfig, Conversation, FallbackStep, IndexKind, LedgerConfig, LimitTier, Message, MessageType,
+    OutcallStats, PaymentToken, PremiumConfig, Role, RoleChange, Shortcut, StarPayment, RateLimits, ResponseCacheConfig, RetentionConfig, RetentionStats, ResponseCacheStats, RetryPolicy, Usage, UsageKey, UsagePeriod, UsageRecord, UsageSubject, UserRole, UserSummary, UserUsage,
+};
 
 type UserDataStore = BTreeMap<String, Message>;
-type PromptStore = BTreeMap<String, String>;
-type UsernameStore = Vec<String>;
+type InlineCacheStore = BTreeMap<String, (u64, i64, String)>;
+type InlineRateStore = BTreeMap<i64, (u64, u32)>;
+type IncognitoBufferStore = BTreeMap<(Conversation, i64), (u64, Vec<Message>)>;
+type BucketStore = BTreeMap<(UsageSubject, i64), (u64, u64)>;
+type Memory = VirtualMemory<DefaultMemoryImpl>;
+type UsageStore = StableBTreeMap<UsageKey, UsageRecord, Memory>;
+type ResponseCacheStore = StableBTreeMap<[u8; 32], CachedResponse, Memory>;
+type CreditStore = StableBTreeMap<u64, u128, Memory>;
+type StarPaymentStore = StableBTreeMap<(u64, u64), StarPayment, Memory>;
+type RoleStore = StableBTreeMap<u64, Role, Memory>;
+type RoleAuditStore = StableBTreeMap<u64, RoleChange, Memory>;
+type InviteStore = StableBTreeMap<[u8; 8], (u64, u64), Memory>;
+type UsernameStore = StableBTreeMap<String, (), Memory>;
+type ApiKeyStore = StableBTreeMap<String, ApiKeys, Memory>;
+type PromptStore = StableBTreeMap<String, String, Memory>;
+type IncognitoStore = StableBTreeMap<u64, (), Memory>;
+type FallbackStore = StableBTreeMap<u32, FallbackStep, Memory>;
+type UserTierStore = StableBTreeMap<u64, String, Memory>;
+type ChatSettingsStore = StableBTreeMap<Conversation, ChatSettings, Memory>;
+type AccessRequestStore = StableBTreeMap<u64, AccessRequest, Memory>;
+type ConfigCell<T> = RefCell<StableCell<T, Memory>>;
 
-struct Config {
-    model: String,
-    prompt: String,
-    
-}  
+const MINUTE: u64 = 60 * 1_000_000_000;
+const DAY: u64 = 24 * 60 * MINUTE;
+const INLINE_CACHE_TTL: u64 = 60 * MINUTE;
+const INLINE_CACHE_SIZE: usize = 500;
+const INLINE_RATE_LIMIT: u32 = 5; // completions per minute
+const INCOGNITO_TTL: u64 = 10 * MINUTE;
+const MAX_NOTICES: usize = 10; // per chat
+
+const DEFAULT_TIER: &str = "default";
+const PREMIUM_TIER: &str = "premium";
+const MILLI: u128 = 1_000; // bucket levels are kept in thousandths of a token
+
+const USAGE_MEMORY_ID: MemoryId = MemoryId::new(0);
+const RESPONSE_CACHE_MEMORY_ID: MemoryId = MemoryId::new(1);
+const CREDIT_MEMORY_ID: MemoryId = MemoryId::new(2);
+const STAR_PAYMENT_MEMORY_ID: MemoryId = MemoryId::new(3);
+const ROLE_MEMORY_ID: MemoryId = MemoryId::new(4);
+const ROLE_AUDIT_MEMORY_ID: MemoryId = MemoryId::new(5);
+const INVITE_MEMORY_ID: MemoryId = MemoryId::new(6);
+const TOKEN_MEMORY_ID: MemoryId = MemoryId::new(7);
+const ADMIN_MEMORY_ID: MemoryId = MemoryId::new(8);
+const USERNAME_MEMORY_ID: MemoryId = MemoryId::new(9);
+const API_KEY_MEMORY_ID: MemoryId = MemoryId::new(10);
+const BACKEND_MEMORY_ID: MemoryId = MemoryId::new(11);
+const FALLBACK_MEMORY_ID: MemoryId = MemoryId::new(12);
+const RETRY_POLICY_MEMORY_ID: MemoryId = MemoryId::new(13);
+const RATE_LIMITS_MEMORY_ID: MemoryId = MemoryId::new(14);
+const USER_TIER_MEMORY_ID: MemoryId = MemoryId::new(15);
+const BUDGET_MEMORY_ID: MemoryId = MemoryId::new(16);
+const RESPONSE_CACHE_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(17);
+const TOOLS_ENABLED_MEMORY_ID: MemoryId = MemoryId::new(18);
+const LEDGER_MEMORY_ID: MemoryId = MemoryId::new(19);
+const CREDIT_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(20);
+const PREMIUM_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(21);
+const RETENTION_CONFIG_MEMORY_ID: MemoryId = MemoryId::new(22);
+const ADMIN_ID_MEMORY_ID: MemoryId = MemoryId::new(23);
+const WEBHOOK_SECRET_MEMORY_ID: MemoryId = MemoryId::new(24);
+const CONFIG_MEMORY_ID: MemoryId = MemoryId::new(25);
+const PROMPT_MEMORY_ID: MemoryId = MemoryId::new(26);
+const INCOGNITO_MEMORY_ID: MemoryId = MemoryId::new(27);
+const SECRET_MEMORY_ID: MemoryId = MemoryId::new(28);
+const CHAT_SETTINGS_MEMORY_ID: MemoryId = MemoryId::new(29);
+const BALANCE_SAMPLE_MEMORY_ID: MemoryId = MemoryId::new(30);
+const ACCESS_REQUEST_MEMORY_ID: MemoryId = MemoryId::new(31);
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            model: "gpt-4o".to_string(),
+            prompt: "You are a helpful assistant.".to_string(),
+        }
+    }
+}
+
+impl Default for BackendConfig {
+    fn default() -> Self {
+        BackendConfig {
+            kind: BackendKind::Proxy,
+            base_url: PROXY_URL.to_string(),
+            headers: vec![],
+            models: vec![],
+        }
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            chat_bucket: BucketConfig {
+                capacity: 20,
+                refill_per_minute: 20,
+            },
+            tiers: vec![
+                LimitTier {
+                    name: DEFAULT_TIER.to_string(),
+                    bucket: BucketConfig {
+                        capacity: 5,
+                        refill_per_minute: 5,
+                    },
+                    daily_chats: Some(100),
+                    daily_images: Some(10),
+                },
+                LimitTier {
+                    name: PREMIUM_TIER.to_string(),
+                    bucket: BucketConfig {
+                        capacity: 20,
+                        refill_per_minute: 10,
+                    },
+                    daily_chats: Some(1_000),
+                    daily_images: Some(100),
+                },
+            ],
+        }
+    }
+}
+
+impl Default for BudgetConfig {
+    fn default() -> Self {
+        BudgetConfig {
+            reserve_cycles: 1_000_000_000_000,
+            daily_spend_cap: None,
+            alert_chat_id: None,
+            warning_levels: vec![5_000_000_000_000, 2_000_000_000_000, 1_000_000_000_000],
+            burn_rate_alert: None,
+        }
+    }
+}
+
+impl Default for ResponseCacheConfig {
+    fn default() -> Self {
+        ResponseCacheConfig {
+            enabled: false,
+            ttl_secs: 24 * 60 * 60,
+            max_entries: 1_000,
+        }
+    }
+}
+
+/// The ICP ledger and its index canister.
+impl Default for LedgerConfig {
+    fn default() -> Self {
+        LedgerConfig {
+            ledger_canister_id: Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(),
+            index_canister_id: Some(Principal::from_text("qhbym-qaaaa-aaaaa-aaafq-cai").unwrap()),
+            index_kind: IndexKind::Icp,
+            symbol: "ICP".to_string(),
+            decimals: 8,
+        }
+    }
+}
+
+/// Rough prices; admins are expected to set their own.
+impl Default for CreditConfig {
+    fn default() -> Self {
+        CreditConfig {
+            enabled: false,
+            tokens: vec![
+                PaymentToken {
+                    symbol: "ICP".to_string(),
+                    ledger_canister_id: Principal::from_text("ryjl3-tyaaa-aaaaa-aaaba-cai").unwrap(),
+                    decimals: 8,
+                    credits_per_unit: 30_000,
+                },
+                PaymentToken {
+                    symbol: "ckBTC".to_string(),
+                    ledger_canister_id: Principal::from_text("mxzaz-hqaaa-aaaar-qaada-cai").unwrap(),
+                    decimals: 8,
+                    credits_per_unit: 450_000,
+                },
+            ],
+            credits_per_llm_token: 5_000_000,
+        }
+    }
+}
+
+impl Default for PremiumConfig {
+    fn default() -> Self {
+        PremiumConfig {
+            enabled: false,
+            price_stars: 250,
+            period_days: 30,
+            tier: PREMIUM_TIER.to_string(),
+            model: None,
+            title: "Premium".to_string(),
+            description: "30 days of higher limits".to_string(),
+        }
+    }
+}
+
+/// Keeps messages for 30 days, as before retention was configurable.
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        RetentionConfig {
+            max_age_days: Some(30),
+            max_messages_per_user: None,
+            max_total_bytes: None,
+            sweep_interval_secs: 60 * 60,
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            initial_backoff_secs: 2,
+            backoff_multiplier: 2,
+        }
+    }
+}
 
 thread_local! {
     pub static USER_DATA_STORE: RefCell<UserDataStore> = RefCell::default();
 
-    pub static PROMPT_STORE: RefCell<String> = RefCell::default();
+    pub static INLINE_CACHE_STORE: RefCell<InlineCacheStore> = RefCell::default();
+
+    pub static INLINE_RATE_STORE: RefCell<InlineRateStore> = RefCell::default();
+
+    /// The turns of incognito users, per conversation, with the time of the
+    /// latest one. Never written anywhere else.
+    pub static INCOGNITO_BUFFER_STORE: RefCell<IncognitoBufferStore> = RefCell::default();
+
+    pub static BOT_USERNAME_STORE: RefCell<String> = RefCell::default();
+
+    /// The key in use for every provider.
+    pub static API_KEY_INDEX: RefCell<BTreeMap<String, usize>> = RefCell::default();
+
+    pub static OUTCALL_STATS_STORE: RefCell<OutcallStats> = RefCell::default();
+
+    pub static BUCKET_STORE: RefCell<BucketStore> = RefCell::default();
+
+    /// Notices from timers waiting for the next reply in their chat, as a
+    /// message sent from a timer would go out once per replica.
+    pub static NOTICE_STORE: RefCell<BTreeMap<i64, Vec<String>>> = RefCell::default();
+
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+
+    /// The bot token, the admin username and the username whitelist of the
+    /// init argument. Kept in stable memory, as `post_upgrade` gets no
+    /// argument to restore them from.
+    pub static TOKEN_STORE: ConfigCell<String> = config_cell(TOKEN_MEMORY_ID, String::new());
+
+    pub static ADMIN_STORE: ConfigCell<String> = config_cell(ADMIN_MEMORY_ID, String::new());
+
+    /// The user id of the admin username, learned when the admin first
+    /// shows up, so they keep their role where only the id is known.
+    pub static ADMIN_ID_STORE: ConfigCell<Option<u64>> = config_cell(ADMIN_ID_MEMORY_ID, None);
+
+    /// The random webhook path that replaced the token, once rotated.
+    pub static WEBHOOK_SECRET_STORE: ConfigCell<String> = config_cell(WEBHOOK_SECRET_MEMORY_ID, String::new());
+
+    pub static CONFIG_STORE: ConfigCell<Config> = config_cell(CONFIG_MEMORY_ID, Config::default());
+
+    /// Signs the callback data of inline buttons. Kept in stable memory, so
+    /// buttons sent before an upgrade keep working.
+    pub static SECRET_STORE: ConfigCell<Vec<u8>> = config_cell(SECRET_MEMORY_ID, vec![]);
+
+    /// Users who turned incognito on, by Telegram user id. Kept in stable
+    /// memory, so an upgrade does not turn it off behind their back.
+    pub static INCOGNITO_STORE: RefCell<IncognitoStore> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|memory_manager| memory_manager.borrow().get(INCOGNITO_MEMORY_ID)),
+    ));
+
+    /// What chat admins set, by chat and topic.
+    pub static CHAT_SETTINGS_STORE: RefCell<ChatSettingsStore> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|memory_manager| memory_manager.borrow().get(CHAT_SETTINGS_MEMORY_ID)),
+    ));
+
+    /// Prompts by shortcut.
+    pub static PROMPT_STORE: RefCell<PromptStore> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|memory_manager| memory_manager.borrow().get(PROMPT_MEMORY_ID)),
+    ));
+
+    pub static USERNAME_STORE: RefCell<UsernameStore> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|memory_manager| memory_manager.borrow().get(USERNAME_MEMORY_ID)),
+    ));
+
+    /// API keys by the base URL of their provider.
+    pub static API_KEY_STORE: RefCell<ApiKeyStore> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|memory_manager| memory_manager.borrow().get(API_KEY_MEMORY_ID)),
+    ));
+
+    /// What the controllers configured. Kept in stable memory, so it
+    /// survives upgrades.
+    pub static BACKEND_STORE: ConfigCell<BackendConfig> = config_cell(BACKEND_MEMORY_ID, BackendConfig::default());
+
+    /// The fallback chain, by position.
+    pub static FALLBACK_STORE: RefCell<FallbackStore> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|memory_manager| memory_manager.borrow().get(FALLBACK_MEMORY_ID)),
+    ));
+
+    pub static RETRY_POLICY_STORE: ConfigCell<RetryPolicy> = config_cell(RETRY_POLICY_MEMORY_ID, RetryPolicy::default());
+
+    pub static RATE_LIMITS_STORE: ConfigCell<RateLimits> = config_cell(RATE_LIMITS_MEMORY_ID, RateLimits::default());
+
+    /// Tier names by Telegram user id.
+    pub static USER_TIER_STORE: RefCell<UserTierStore> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|memory_manager| memory_manager.borrow().get(USER_TIER_MEMORY_ID)),
+    ));
 
-    pub static USERNAME_STORE: RefCell<UsernameStore> = RefCell::default();
+    pub static BUDGET_STORE: ConfigCell<BudgetConfig> = config_cell(BUDGET_MEMORY_ID, BudgetConfig::default());
 
-    pub static ADMIN_STORE: RefCell<String> = RefCell::default();
+    /// Kept in stable memory, so an upgrade does not repeat the alerts.
+    pub static BALANCE_SAMPLE_STORE: ConfigCell<BalanceSample> =
+        config_cell(BALANCE_SAMPLE_MEMORY_ID, BalanceSample::default());
 
-    pub static TOKEN_STORE: RefCell<String> = RefCell::default();
+    pub static RESPONSE_CACHE_CONFIG_STORE: ConfigCell<ResponseCacheConfig> =
+        config_cell(RESPONSE_CACHE_CONFIG_MEMORY_ID, ResponseCacheConfig::default());
+
+    pub static TOOLS_ENABLED_STORE: ConfigCell<bool> = config_cell(TOOLS_ENABLED_MEMORY_ID, true);
+
+    pub static LEDGER_STORE: ConfigCell<LedgerConfig> = config_cell(LEDGER_MEMORY_ID, LedgerConfig::default());
+
+    pub static CREDIT_CONFIG_STORE: ConfigCell<CreditConfig> = config_cell(CREDIT_CONFIG_MEMORY_ID, CreditConfig::default());
+
+    pub static PREMIUM_CONFIG_STORE: ConfigCell<PremiumConfig> =
+        config_cell(PREMIUM_CONFIG_MEMORY_ID, PremiumConfig::default());
+
+    pub static RETENTION_CONFIG_STORE: ConfigCell<RetentionConfig> =
+        config_cell(RETENTION_CONFIG_MEMORY_ID, RetentionConfig::default());
+
+    /// Kept in stable memory, so it survives upgrades.
+    pub static USAGE_STORE: RefCell<UsageStore> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|memory_manager| memory_manager.borrow().get(USAGE_MEMORY_ID)),
+    ));
+
+    pub static RESPONSE_CACHE_STORE: RefCell<ResponseCacheStore> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|memory_manager| memory_manager.borrow().get(RESPONSE_CACHE_MEMORY_ID)),
+    ));
+
+    /// Credits per Telegram user id.
+    pub static CREDIT_STORE: RefCell<CreditStore> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|memory_manager| memory_manager.borrow().get(CREDIT_MEMORY_ID)),
+    ));
+
+    /// Stars receipts by user id and payment time.
+    pub static STAR_PAYMENT_STORE: RefCell<StarPaymentStore> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|memory_manager| memory_manager.borrow().get(STAR_PAYMENT_MEMORY_ID)),
+    ));
+
+    /// Roles by Telegram user id.
+    pub static ROLE_STORE: RefCell<RoleStore> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|memory_manager| memory_manager.borrow().get(ROLE_MEMORY_ID)),
+    ));
+
+    /// Access requests by Telegram user id.
+    pub static ACCESS_REQUEST_STORE: RefCell<AccessRequestStore> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|memory_manager| memory_manager.borrow().get(ACCESS_REQUEST_MEMORY_ID)),
+    ));
+
+    /// Unused invite codes, with who made them and when.
+    pub static INVITE_STORE: RefCell<InviteStore> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|memory_manager| memory_manager.borrow().get(INVITE_MEMORY_ID)),
+    ));
+
+    /// Every role change, numbered from 0.
+    pub static ROLE_AUDIT_STORE: RefCell<RoleAuditStore> = RefCell::new(StableBTreeMap::init(
+        MEMORY_MANAGER.with(|memory_manager| memory_manager.borrow().get(ROLE_AUDIT_MEMORY_ID)),
+    ));
+
+    /// Users who asked for a deposit address, until when their deposit
+    /// subaccounts are swept.
+    pub static DEPOSIT_WATCH_STORE: RefCell<BTreeMap<i64, u64>> = RefCell::default();
+
+    pub static RETENTION_STATS_STORE: RefCell<RetentionStats> = RefCell::default();
+
+    pub static RESPONSE_CACHE_STATS_STORE: RefCell<ResponseCacheStats> = RefCell::default();
 }
 
-pub fn get_followed_messages(username: String) -> Vec<Message> {
+/// The user's current thread in the conversation. Every member of a group
+/// has their own, so nobody can continue or retry somebody else's.
+pub fn get_followed_messages(conversation: Conversation, user_id: i64) -> Vec<Message> {
     USER_DATA_STORE.with(|user_data_store| {
         let mut messages: Vec<Message> = user_data_store
             .borrow()
             .iter()
-            .filter(|(_, message)| message.username == username)
+            .filter(|(_, message)| message.conversation == conversation && message.user_id == user_id)
             .map(|(_, message)| message.clone())
             .collect();
-        messages.sort_by(|a, b| a.date.cmp(&b.date));
+        messages.sort_by_key(|message| message.date);
         let mut followed_message = vec![];
-        let mut flag = true;
-        messages.iter().for_each(|message| {
-            if flag {
-                followed_message.push(message.clone());
-                flag = message.is_follow;
-            } else {
-                return;
+        for message in messages {
+            if !followed_message.is_empty() && !message.is_follow {
+                break;
             }
-        });
+            followed_message.push(message);
+        }
         followed_message
     })
 }
 
-pub fn get_latest_messages(username: String) -> Option<Message> {
+pub fn get_latest_messages(conversation: Conversation, user_id: i64) -> Option<Message> {
     USER_DATA_STORE.with(|user_data_store| {
         let mut messages: Vec<Message> = user_data_store
             .borrow()
             .iter()
-            .filter(|(_, message)| message.username == username)
+            .filter(|(_, message)| message.conversation == conversation && message.user_id == user_id)
             .map(|(_, message)| message.clone())
             .collect();
-        messages.sort_by(|a, b| a.date.cmp(&b.date));
-        messages.into_iter().next()
+        messages.sort_by_key(|message| message.date);
+        messages.pop()
     })
 }
 
+#[allow(clippy::too_many_arguments)]
 pub fn add_new_messages(
     key: String,
+    conversation: Conversation,
+    user_id: i64,
     username: String,
     types: MessageType,
     date: u64,
     question: String,
     answer: String,
     is_follow: bool,
+    usage: Usage,
+    model: String,
 ) {
-    delete_messages(username.clone(), is_follow);
+    delete_messages(conversation, user_id, is_follow);
     USER_DATA_STORE.with(|user_data_store| {
         let new_message = Message {
+            conversation,
+            user_id,
             username,
             date,
             types,
             question,
             answer,
             is_follow,
+            usage,
+            model,
         };
         user_data_store.borrow_mut().insert(key, new_message);
     });
 }
 
-pub fn delete_messages(username: String, is_follow: bool) {
-    let time = ic_cdk::api::time();
-    let interval: u64 = 30 * 24 * 60 * 60 * 1_000_000_000; // a month in nanosecond
-    let mut old_message_keys: Vec<String> = vec![];
+/// A question that is not a follow-up starts over, so the user's earlier
+/// messages in the conversation are dropped. Old messages are left to the
+/// retention sweep.
+pub fn delete_messages(conversation: Conversation, user_id: i64, is_follow: bool) {
+    if !is_follow {
+        clear_messages(conversation, user_id);
+    }
+}
+
+pub fn remove_message(key: String) {
     USER_DATA_STORE.with(|user_data_store| {
-        let binding = user_data_store.borrow();
-        binding
-            .iter()
-            .filter(|(_, message)| message.username == username && (!is_follow || time - message.date > interval))
-            .for_each(|(key, _)| old_message_keys.push(key.clone()))
+        user_data_store.borrow_mut().remove(&key);
     });
+}
+
+pub fn clear_messages(conversation: Conversation, user_id: i64) {
     USER_DATA_STORE.with(|user_data_store| {
-        let mut binding = user_data_store.borrow_mut();
-        old_message_keys.iter().for_each(|key| {
-            let _ = binding.remove(key);
-        })
+        user_data_store
+            .borrow_mut()
+            .retain(|_, message| message.conversation != conversation || message.user_id != user_id);
     });
 }
 
 pub fn is_admin(admin: String) -> bool {
-    ADMIN_STORE.with(|admin_store| {
-        if admin == admin_store.borrow().clone() {
-            true
-        } else {
-            false
+    let admin_username = get_admin();
+    !admin_username.is_empty() && admin == admin_username
+}
+
+pub fn get_admin() -> String {
+    ADMIN_STORE.with(|admin_store| admin_store.borrow().get().clone())
+}
+
+/// A new admin username forgets the id of the previous admin.
+pub fn set_admin(admin: String) {
+    if admin != get_admin() {
+        ADMIN_ID_STORE.with(|admin_id_store| set_config_cell(admin_id_store, None));
+    }
+    ADMIN_STORE.with(|admin_store| set_config_cell(admin_store, admin));
+}
+
+pub fn get_admin_id() -> Option<i64> {
+    ADMIN_ID_STORE.with(|admin_id_store| admin_id_store.borrow().get().map(|user_id| user_id as i64))
+}
+
+/// The webhook path is the bot token until a controller rotates it to a
+/// random secret. Without either, nothing gets in.
+pub fn is_token_valid(token: String) -> bool {
+    let secret = get_webhook_secret();
+    if !secret.is_empty() {
+        return token == secret;
+    }
+    let bot_token = get_token();
+    !bot_token.is_empty() && token == bot_token
+}
+
+pub fn get_webhook_secret() -> String {
+    WEBHOOK_SECRET_STORE.with(|webhook_secret_store| webhook_secret_store.borrow().get().clone())
+}
+
+pub fn set_webhook_secret(secret: String) {
+    WEBHOOK_SECRET_STORE.with(|webhook_secret_store| set_config_cell(webhook_secret_store, secret));
+}
+
+pub fn get_token() -> String {
+    TOKEN_STORE.with(|token_store| token_store.borrow().get().clone())
+}
+
+pub fn set_token(token: String) {
+    TOKEN_STORE.with(|token_store| set_config_cell(token_store, token));
+}
+
+pub fn is_user(username: String) -> bool {
+    USERNAME_STORE.with(|username_store| {
+        let username_store = username_store.borrow();
+        username_store.is_empty() || username_store.contains_key(&username)
+    })
+}
+
+pub fn get_usernames() -> Vec<String> {
+    USERNAME_STORE.with(|username_store| username_store.borrow().iter().map(|(username, _)| username).collect())
+}
+
+pub fn set_usernames(usernames: Vec<String>) {
+    USERNAME_STORE.with(|username_store| {
+        let mut binding = username_store.borrow_mut();
+        let old: Vec<String> = binding.iter().map(|(username, _)| username).collect();
+        for username in old {
+            binding.remove(&username);
+        }
+        for username in usernames {
+            binding.insert(username, ());
         }
+    });
+}
+
+pub fn get_prompt() -> String {
+    CONFIG_STORE.with(|config_store| config_store.borrow().get().prompt.clone())
+}
+
+pub fn get_model() -> String {
+    CONFIG_STORE.with(|config_store| config_store.borrow().get().model.clone())
+}
+
+pub fn get_chat_settings(conversation: Conversation) -> ChatSettings {
+    CHAT_SETTINGS_STORE.with(|chat_settings_store| {
+        chat_settings_store.borrow().get(&conversation).unwrap_or_default()
     })
 }
 
-pub fn is_token_valid(token: String) -> bool {
-    TOKEN_STORE.with(|token_store| {
-        if token == token_store.borrow().clone() {
-            true
-        } else {
-            false
+pub fn set_chat_settings(conversation: Conversation, settings: ChatSettings) {
+    CHAT_SETTINGS_STORE.with(|chat_settings_store| {
+        chat_settings_store.borrow_mut().insert(conversation, settings);
+    });
+}
+
+/// Topic settings first, then the settings of the whole chat, then the
+/// global config.
+pub fn get_chat_prompt(conversation: Conversation) -> String {
+    let chat = Conversation { thread_id: 0, ..conversation };
+    get_chat_settings(conversation)
+        .prompt
+        .or_else(|| get_chat_settings(chat).prompt)
+        .unwrap_or_else(get_prompt)
+}
+
+pub fn get_chat_model(conversation: Conversation) -> String {
+    let chat = Conversation { thread_id: 0, ..conversation };
+    get_chat_settings(conversation)
+        .model
+        .or_else(|| get_chat_settings(chat).model)
+        .unwrap_or_else(get_model)
+}
+
+/// Like `get_chat_model`, but premium users get the premium model unless
+/// the chat has its own. The premium model is theirs only, even when a chat
+/// chose it.
+pub fn get_user_chat_model(conversation: Conversation, user_id: i64) -> String {
+    let chat = Conversation { thread_id: 0, ..conversation };
+    let premium_model = get_premium_config().model;
+    let is_premium = is_premium(user_id);
+    let allowed = |model: &String| is_premium || premium_model.as_ref() != Some(model);
+    get_chat_settings(conversation)
+        .model
+        .filter(allowed)
+        .or_else(|| get_chat_settings(chat).model.filter(allowed))
+        .or(premium_model.clone().filter(|_| is_premium))
+        .unwrap_or_else(get_model)
+}
+
+pub fn get_bot_username() -> String {
+    BOT_USERNAME_STORE.with(|bot_username_store| bot_username_store.borrow().clone())
+}
+
+pub fn set_bot_username(username: String) {
+    BOT_USERNAME_STORE.with(|bot_username_store| {
+        *bot_username_store.borrow_mut() = username;
+    });
+}
+
+pub fn get_secret() -> Vec<u8> {
+    SECRET_STORE.with(|secret_store| secret_store.borrow().get().clone())
+}
+
+pub fn set_secret(secret: Vec<u8>) {
+    SECRET_STORE.with(|secret_store| set_config_cell(secret_store, secret));
+}
+
+pub fn get_shortcut(shortcut: &str) -> Option<String> {
+    PROMPT_STORE.with(|prompt_store| prompt_store.borrow().get(&shortcut.to_string()))
+}
+
+/// Adds the shortcuts, replacing prompts of the same name.
+pub fn add_shortcuts(shortcuts: Vec<Shortcut>) {
+    PROMPT_STORE.with(|prompt_store| {
+        let mut binding = prompt_store.borrow_mut();
+        for shortcut in shortcuts {
+            binding.insert(shortcut.shortcut, shortcut.prompt);
         }
+    });
+}
+
+pub fn find_shortcuts(prefix: &str) -> Vec<(String, String)> {
+    PROMPT_STORE.with(|prompt_store| {
+        prompt_store
+            .borrow()
+            .iter()
+            .filter(|(shortcut, _)| shortcut.starts_with(prefix))
+            .collect()
     })
 }
 
-pub fn is_user(username: String) -> bool {
-    USERNAME_STORE.with(|username_store| {
-        if username_store.borrow().len() == 0 {
+pub fn get_inline_cache(query: &str) -> Option<String> {
+    let time = ic_cdk::api::time();
+    INLINE_CACHE_STORE.with(|inline_cache_store| {
+        inline_cache_store
+            .borrow()
+            .get(query)
+            .filter(|(date, _, _)| time - date < INLINE_CACHE_TTL)
+            .map(|(_, _, answer)| answer.clone())
+    })
+}
+
+/// Keeps who asked, so that forgetting the user drops the answer.
+pub fn set_inline_cache(query: String, user_id: i64, answer: String) {
+    let time = ic_cdk::api::time();
+    INLINE_CACHE_STORE.with(|inline_cache_store| {
+        let mut binding = inline_cache_store.borrow_mut();
+        binding.retain(|_, (date, _, _)| time - *date < INLINE_CACHE_TTL);
+        if binding.len() >= INLINE_CACHE_SIZE {
+            let oldest = binding
+                .iter()
+                .min_by_key(|(_, (date, _, _))| *date)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                binding.remove(&oldest);
+            }
+        }
+        binding.insert(query, (time, user_id, answer));
+    });
+}
+
+/// Counts an inline completion against the user's per-minute budget and
+/// returns whether it is allowed.
+pub fn take_inline_rate(user_id: i64) -> bool {
+    let time = ic_cdk::api::time();
+    INLINE_RATE_STORE.with(|inline_rate_store| {
+        let mut binding = inline_rate_store.borrow_mut();
+        let (window, count) = binding.entry(user_id).or_insert((time, 0));
+        if time - *window >= MINUTE {
+            *window = time;
+            *count = 0;
+        }
+        if *count >= INLINE_RATE_LIMIT {
+            false
+        } else {
+            *count += 1;
             true
+        }
+    })
+}
+
+/// A cell in its own virtual memory, holding `default` until first set.
+fn config_cell<T: Storable>(memory_id: MemoryId, default: T) -> ConfigCell<T> {
+    RefCell::new(
+        StableCell::init(
+            MEMORY_MANAGER.with(|memory_manager| memory_manager.borrow().get(memory_id)),
+            default,
+        )
+        .expect("Failed to init a config store"),
+    )
+}
+
+fn set_config_cell<T: Storable>(cell: &ConfigCell<T>, value: T) {
+    cell.borrow_mut().set(value).expect("Failed to write a config store");
+}
+
+pub fn get_backend() -> BackendConfig {
+    BACKEND_STORE.with(|backend_store| backend_store.borrow().get().clone())
+}
+
+pub fn set_backend(config: BackendConfig) {
+    BACKEND_STORE.with(|backend_store| set_config_cell(backend_store, config));
+}
+
+/// Replaces the keys of the provider at `base_url`; no keys removes it.
+pub fn set_api_keys(base_url: String, keys: Vec<String>) {
+    API_KEY_STORE.with(|api_key_store| {
+        let mut binding = api_key_store.borrow_mut();
+        if keys.is_empty() {
+            binding.remove(&base_url);
         } else {
-            if username_store.borrow().contains(&username) {
-                true
-            } else {
-                false
+            binding.insert(base_url.clone(), ApiKeys { keys });
+        }
+    });
+    API_KEY_INDEX.with(|api_key_index| {
+        api_key_index.borrow_mut().remove(&base_url);
+    });
+}
+
+fn api_keys(base_url: &str) -> Vec<String> {
+    API_KEY_STORE.with(|api_key_store| {
+        api_key_store
+            .borrow()
+            .get(&base_url.to_string())
+            .map(|api_keys| api_keys.keys)
+            .unwrap_or_default()
+    })
+}
+
+pub fn api_key_count(base_url: &str) -> usize {
+    api_keys(base_url).len()
+}
+
+pub fn current_api_key(base_url: &str) -> Option<String> {
+    let keys = api_keys(base_url);
+    let index = API_KEY_INDEX.with(|api_key_index| api_key_index.borrow().get(base_url).copied().unwrap_or_default());
+    keys.get(index % keys.len().max(1)).cloned()
+}
+
+/// Moves on to the provider's next key, used when the current one is rate
+/// limited.
+pub fn rotate_api_key(base_url: &str) {
+    let count = api_key_count(base_url).max(1);
+    API_KEY_INDEX.with(|api_key_index| {
+        let mut binding = api_key_index.borrow_mut();
+        let index = binding.entry(base_url.to_string()).or_default();
+        *index = (*index + 1) % count;
+    });
+}
+
+/// Replaces every API key found in `text`, so keys never end up in logs or
+/// chat messages.
+pub fn redact_api_keys(text: &str) -> String {
+    API_KEY_STORE.with(|api_key_store| {
+        api_key_store
+            .borrow()
+            .iter()
+            .flat_map(|(_, api_keys)| api_keys.keys)
+            .filter(|key| !key.is_empty())
+            .fold(text.to_string(), |text, key| text.replace(key.as_str(), "***"))
+    })
+}
+
+pub fn get_fallback_chain() -> Vec<FallbackStep> {
+    FALLBACK_STORE.with(|fallback_store| fallback_store.borrow().iter().map(|(_, step)| step).collect())
+}
+
+pub fn set_fallback_chain(chain: Vec<FallbackStep>) {
+    FALLBACK_STORE.with(|fallback_store| {
+        let mut binding = fallback_store.borrow_mut();
+        let positions: Vec<u32> = binding.iter().map(|(position, _)| position).collect();
+        positions.iter().for_each(|position| {
+            binding.remove(position);
+        });
+        chain.into_iter().enumerate().for_each(|(position, step)| {
+            binding.insert(position as u32, step);
+        });
+    });
+}
+
+pub fn get_retry_policy() -> RetryPolicy {
+    RETRY_POLICY_STORE.with(|retry_policy_store| retry_policy_store.borrow().get().clone())
+}
+
+pub fn set_retry_policy(policy: RetryPolicy) {
+    RETRY_POLICY_STORE.with(|retry_policy_store| set_config_cell(retry_policy_store, policy));
+}
+
+pub fn record_outcall(attached: u128, refunded: u128) {
+    OUTCALL_STATS_STORE.with(|outcall_stats_store| {
+        let mut stats = outcall_stats_store.borrow_mut();
+        stats.outcalls += 1;
+        stats.cycles_attached += attached;
+        stats.cycles_refunded += refunded;
+    });
+}
+
+pub fn get_outcall_stats() -> OutcallStats {
+    OUTCALL_STATS_STORE.with(|outcall_stats_store| outcall_stats_store.borrow().clone())
+}
+
+/// Days or months since the Unix epoch.
+pub fn period_index(period: UsagePeriod, time: u64) -> u32 {
+    let days = (time / DAY) as i64;
+    match period {
+        UsagePeriod::Day => days as u32,
+        UsagePeriod::Month => {
+            let (year, month, _) = civil_from_days(days);
+            ((year - 1970) * 12 + month - 1) as u32
+        }
+    }
+}
+
+/// Formats a timestamp as `2024-05-17 13:45:00 UTC`.
+pub fn format_time(time: u64) -> String {
+    let seconds = time / 1_000_000_000;
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    let seconds = seconds % 86_400;
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02} UTC",
+        year,
+        month,
+        day,
+        seconds / 3_600,
+        seconds % 3_600 / 60,
+        seconds % 60
+    )
+}
+
+/// The civil year, month and day of a day since the epoch, see
+/// http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Adds one completion to the daily and monthly records of the user and
+/// of the chat it was asked in. Inline queries have no chat (`chat_id` 0).
+/// A failed attempt has no `usage` and only adds its cycles.
+pub fn record_usage(user_id: i64, chat_id: i64, types: MessageType, usage: Option<Usage>, cycles: u128) {
+    let now = ic_cdk::api::time();
+    let mut subjects = vec![(UsageSubject::Global, 0), (UsageSubject::User, user_id)];
+    if chat_id != 0 {
+        subjects.push((UsageSubject::Chat, chat_id));
+    }
+    USAGE_STORE.with(|usage_store| {
+        let mut usage_store = usage_store.borrow_mut();
+        for (subject, id) in subjects {
+            for period in [UsagePeriod::Day, UsagePeriod::Month] {
+                let key = UsageKey {
+                    subject,
+                    id,
+                    period,
+                    index: period_index(period, now),
+                };
+                let mut record = usage_store.get(&key).unwrap_or_default();
+                if let Some(usage) = usage {
+                    match types {
+                        MessageType::Chat => record.requests += 1,
+                        MessageType::Image => record.images += 1,
+                    }
+                    record.prompt_tokens += usage.prompt_tokens;
+                    record.completion_tokens += usage.completion_tokens;
+                }
+                record.cycles += cycles;
+                usage_store.insert(key, record);
             }
         }
+    });
+}
+
+/// The usage of a user or chat in the current day or month.
+pub fn get_usage(subject: UsageSubject, id: i64, period: UsagePeriod) -> UsageRecord {
+    let key = UsageKey {
+        subject,
+        id,
+        period,
+        index: period_index(period, ic_cdk::api::time()),
+    };
+    USAGE_STORE.with(|usage_store| usage_store.borrow().get(&key).unwrap_or_default())
+}
+
+/// The users who spent the most cycles in the current day or month.
+pub fn top_spenders(period: UsagePeriod, limit: usize) -> Vec<UserUsage> {
+    let index = period_index(period, ic_cdk::api::time());
+    let mut users: Vec<UserUsage> = USAGE_STORE.with(|usage_store| {
+        usage_store
+            .borrow()
+            .iter()
+            .filter(|(key, _)| key.subject == UsageSubject::User && key.period == period && key.index == index)
+            .map(|(key, usage)| UserUsage {
+                user_id: key.id,
+                usage,
+            })
+            .collect()
+    });
+    users.sort_by_key(|user| std::cmp::Reverse(user.usage.cycles));
+    users.truncate(limit);
+    users
+}
+
+pub fn get_rate_limits() -> RateLimits {
+    RATE_LIMITS_STORE.with(|rate_limits_store| rate_limits_store.borrow().get().clone())
+}
+
+pub fn set_rate_limits(limits: RateLimits) {
+    RATE_LIMITS_STORE.with(|rate_limits_store| set_config_cell(rate_limits_store, limits));
+}
+
+/// Assigns a tier to a user, or puts them back on the default tier.
+pub fn set_user_tier(user_id: i64, tier: Option<String>) {
+    USER_TIER_STORE.with(|user_tier_store| {
+        let mut binding = user_tier_store.borrow_mut();
+        match tier {
+            Some(tier) => binding.insert(user_id as u64, tier),
+            None => binding.remove(&(user_id as u64)),
+        };
+    });
+}
+
+/// Premium users and paying users, while their period lasts, are in the
+/// premium tier whatever tier they were assigned.
+pub fn get_user_tier(user_id: i64) -> Option<LimitTier> {
+    let name = if is_premium(user_id) {
+        get_premium_config().tier
+    } else {
+        USER_TIER_STORE
+            .with(|user_tier_store| user_tier_store.borrow().get(&(user_id as u64)))
+            .unwrap_or_else(|| DEFAULT_TIER.to_string())
+    };
+    let tiers = get_rate_limits().tiers;
+    tiers
+        .iter()
+        .find(|tier| tier.name == name)
+        .or_else(|| tiers.iter().find(|tier| tier.name == DEFAULT_TIER))
+        .cloned()
+}
+
+/// Takes a token from the user's bucket and, in groups, from the chat's.
+/// Nothing is taken when one of them is empty; the seconds until it has a
+/// token again are returned instead.
+pub fn take_rate_token(user_id: i64, chat_id: i64, user_bucket: Option<BucketConfig>) -> Result<(), u64> {
+    let now = ic_cdk::api::time();
+    let mut buckets = vec![];
+    if let Some(user_bucket) = user_bucket {
+        buckets.push(((UsageSubject::User, user_id), user_bucket));
+    }
+    if chat_id != user_id && chat_id != 0 {
+        buckets.push(((UsageSubject::Chat, chat_id), get_rate_limits().chat_bucket));
+    }
+    BUCKET_STORE.with(|bucket_store| {
+        let mut binding = bucket_store.borrow_mut();
+        let mut wait = 0;
+        let levels: Vec<u128> = buckets
+            .iter()
+            .map(|(key, config)| {
+                let level = bucket_level(binding.get(key), config, now);
+                if level < MILLI {
+                    let refill = config.refill_per_minute.max(1) as u128;
+                    let nanos = (MILLI - level) * MINUTE as u128 / (refill * MILLI);
+                    wait = wait.max(nanos.div_ceil(1_000_000_000) as u64);
+                }
+                level
+            })
+            .collect();
+        if wait > 0 {
+            return Err(wait);
+        }
+        for ((key, _), level) in buckets.iter().zip(levels) {
+            binding.insert(*key, ((level - MILLI) as u64, now));
+        }
+        Ok(())
     })
 }
 
-pub fn get_prompt() -> String {
+fn bucket_level(state: Option<&(u64, u64)>, config: &BucketConfig, now: u64) -> u128 {
+    let capacity = config.capacity as u128 * MILLI;
+    match state {
+        Some((level, time)) => {
+            let elapsed = now.saturating_sub(*time) as u128;
+            let refilled = elapsed * config.refill_per_minute as u128 * MILLI / MINUTE as u128;
+            (*level as u128 + refilled).min(capacity)
+        }
+        None => capacity,
+    }
+}
+
+/// Nanoseconds until the daily quotas reset, at midnight UTC.
+pub fn until_next_day() -> u64 {
+    DAY - ic_cdk::api::time() % DAY
+}
+
+pub fn get_budget() -> BudgetConfig {
+    BUDGET_STORE.with(|budget_store| budget_store.borrow().get().clone())
+}
+
+pub fn set_budget(budget: BudgetConfig) {
+    BUDGET_STORE.with(|budget_store| set_config_cell(budget_store, budget));
+}
+
+pub fn get_balance_sample() -> BalanceSample {
+    BALANCE_SAMPLE_STORE.with(|balance_sample_store| balance_sample_store.borrow().get().clone())
+}
+
+pub fn set_balance_sample(sample: BalanceSample) {
+    BALANCE_SAMPLE_STORE.with(|balance_sample_store| set_config_cell(balance_sample_store, sample));
+}
+
+/// Queues a notice for the chat, dropping the oldest when too many wait.
+pub fn push_notice(chat_id: i64, notice: String) {
+    NOTICE_STORE.with(|notice_store| {
+        let mut binding = notice_store.borrow_mut();
+        let notices = binding.entry(chat_id).or_default();
+        notices.push(notice);
+        if notices.len() > MAX_NOTICES {
+            notices.remove(0);
+        }
+    });
+}
+
+pub fn take_notices(chat_id: i64) -> Vec<String> {
+    NOTICE_STORE.with(|notice_store| notice_store.borrow_mut().remove(&chat_id).unwrap_or_default())
+}
+
+pub fn get_response_cache() -> ResponseCacheConfig {
+    RESPONSE_CACHE_CONFIG_STORE.with(|response_cache_config_store| response_cache_config_store.borrow().get().clone())
+}
+
+/// Turning the cache off also drops what it holds.
+pub fn set_response_cache(config: ResponseCacheConfig) {
+    if !config.enabled {
+        RESPONSE_CACHE_STORE.with(|response_cache_store| {
+            let mut binding = response_cache_store.borrow_mut();
+            let keys: Vec<[u8; 32]> = binding.iter().map(|(key, _)| key).collect();
+            keys.iter().for_each(|key| {
+                binding.remove(key);
+            });
+        });
+    }
+    RESPONSE_CACHE_CONFIG_STORE.with(|response_cache_config_store| set_config_cell(response_cache_config_store, config));
+}
+
+pub fn get_cached_response(key: &[u8; 32]) -> Option<CachedResponse> {
+    let ttl = get_response_cache().ttl_secs.saturating_mul(1_000_000_000);
+    let time = ic_cdk::api::time();
+    let cached = RESPONSE_CACHE_STORE.with(|response_cache_store| {
+        let mut binding = response_cache_store.borrow_mut();
+        match binding.get(key) {
+            Some(cached) if time - cached.created < ttl => Some(cached),
+            Some(_) => {
+                binding.remove(key);
+                None
+            }
+            None => None,
+        }
+    });
+    RESPONSE_CACHE_STATS_STORE.with(|response_cache_stats_store| {
+        let mut stats = response_cache_stats_store.borrow_mut();
+        match &cached {
+            Some(cached) => {
+                stats.hits += 1;
+                stats.cycles_saved += cached.cycles;
+            }
+            None => stats.misses += 1,
+        }
+    });
+    cached
+}
+
+/// Makes room by dropping expired answers first, then the oldest ones.
+pub fn put_cached_response(key: [u8; 32], response: CachedResponse) {
+    let config = get_response_cache();
+    let ttl = config.ttl_secs.saturating_mul(1_000_000_000);
+    RESPONSE_CACHE_STORE.with(|response_cache_store| {
+        let mut binding = response_cache_store.borrow_mut();
+        if binding.len() >= config.max_entries {
+            let mut entries: Vec<([u8; 32], u64)> =
+                binding.iter().map(|(key, cached)| (key, cached.created)).collect();
+            entries.sort_by_key(|(_, created)| *created);
+            let excess = (binding.len() + 1).saturating_sub(config.max_entries) as usize;
+            entries
+                .iter()
+                .enumerate()
+                .filter(|(index, (_, created))| *index < excess || response.created - created >= ttl)
+                .for_each(|(_, (key, _))| {
+                    binding.remove(key);
+                });
+        }
+        if config.max_entries > 0 {
+            binding.insert(key, response);
+        }
+    });
+}
+
+pub fn response_cache_stats() -> ResponseCacheStats {
+    let entries = RESPONSE_CACHE_STORE.with(|response_cache_store| response_cache_store.borrow().len());
+    RESPONSE_CACHE_STATS_STORE.with(|response_cache_stats_store| ResponseCacheStats {
+        entries,
+        ..response_cache_stats_store.borrow().clone()
+    })
+}
+
+pub fn tools_enabled() -> bool {
+    TOOLS_ENABLED_STORE.with(|tools_enabled_store| *tools_enabled_store.borrow().get())
+}
+
+pub fn set_tools_enabled(enabled: bool) {
+    TOOLS_ENABLED_STORE.with(|tools_enabled_store| set_config_cell(tools_enabled_store, enabled));
+}
+
+pub fn get_ledger() -> LedgerConfig {
+    LEDGER_STORE.with(|ledger_store| ledger_store.borrow().get().clone())
+}
+
+pub fn set_ledger(config: LedgerConfig) {
+    LEDGER_STORE.with(|ledger_store| set_config_cell(ledger_store, config));
+}
+
+pub fn get_credit_config() -> CreditConfig {
+    CREDIT_CONFIG_STORE.with(|credit_config_store| credit_config_store.borrow().get().clone())
+}
+
+pub fn set_credit_config(config: CreditConfig) {
+    CREDIT_CONFIG_STORE.with(|credit_config_store| set_config_cell(credit_config_store, config));
+}
+
+pub fn get_credits(user_id: i64) -> u128 {
+    CREDIT_STORE.with(|credit_store| credit_store.borrow().get(&(user_id as u64)).unwrap_or_default())
+}
+
+pub fn add_credits(user_id: i64, credits: u128) -> u128 {
+    let balance = get_credits(user_id).saturating_add(credits);
+    CREDIT_STORE.with(|credit_store| credit_store.borrow_mut().insert(user_id as u64, balance));
+    balance
+}
+
+/// Completions are paid after the fact, so the balance stops at zero.
+pub fn debit_credits(user_id: i64, credits: u128) -> u128 {
+    let balance = get_credits(user_id).saturating_sub(credits);
+    CREDIT_STORE.with(|credit_store| credit_store.borrow_mut().insert(user_id as u64, balance));
+    balance
+}
+
+pub fn watch_deposits(user_id: i64, until: u64) {
+    DEPOSIT_WATCH_STORE.with(|deposit_watch_store| {
+        deposit_watch_store.borrow_mut().insert(user_id, until);
+    });
+}
+
+/// The users whose deposits are still swept, forgetting expired ones.
+pub fn watched_depositors() -> Vec<i64> {
+    let time = ic_cdk::api::time();
+    DEPOSIT_WATCH_STORE.with(|deposit_watch_store| {
+        let mut binding = deposit_watch_store.borrow_mut();
+        binding.retain(|_, until| *until > time);
+        binding.keys().copied().collect()
+    })
+}
+
+pub fn get_premium_config() -> PremiumConfig {
+    PREMIUM_CONFIG_STORE.with(|premium_config_store| premium_config_store.borrow().get().clone())
+}
+
+pub fn set_premium_config(config: PremiumConfig) {
+    PREMIUM_CONFIG_STORE.with(|premium_config_store| set_config_cell(premium_config_store, config));
+}
+
+pub fn record_star_payment(payment: StarPayment) {
+    STAR_PAYMENT_STORE.with(|star_payment_store| {
+        star_payment_store
+            .borrow_mut()
+            .insert((payment.user_id as u64, payment.paid_at), payment);
+    });
+}
+
+/// The receipts of one user, or of everyone, oldest first.
+pub fn star_payments(user_id: Option<i64>) -> Vec<StarPayment> {
+    STAR_PAYMENT_STORE.with(|star_payment_store| {
+        let binding = star_payment_store.borrow();
+        match user_id {
+            Some(user_id) => binding
+                .range((user_id as u64, 0)..=(user_id as u64, u64::MAX))
+                .map(|(_, payment)| payment)
+                .collect(),
+            None => binding.iter().map(|(_, payment)| payment).collect(),
+        }
+    })
+}
+
+/// When the user's premium period ends, if it has not yet.
+pub fn premium_until(user_id: i64) -> Option<u64> {
+    let time = ic_cdk::api::time();
+    star_payments(Some(user_id))
+        .into_iter()
+        .filter(|payment| payment.refunded_at.is_none())
+        .map(|payment| payment.premium_until)
+        .max()
+        .filter(|until| *until > time)
+}
+
+/// Marks a payment as refunded, which ends the premium period it bought.
+/// Returns the receipt, or `None` if the charge is unknown.
+pub fn mark_refunded(charge_id: &str) -> Option<StarPayment> {
+    STAR_PAYMENT_STORE.with(|star_payment_store| {
+        let mut binding = star_payment_store.borrow_mut();
+        let (key, mut payment) = binding.iter().find(|(_, payment)| payment.charge_id == charge_id)?;
+        if payment.refunded_at.is_none() {
+            payment.refunded_at = Some(ic_cdk::api::time());
+            binding.insert(key, payment.clone());
+        }
+        Some(payment)
+    })
+}
+
+/// Undoes `mark_refunded` when Telegram turned the refund down.
+pub fn clear_refund(charge_id: &str) {
+    STAR_PAYMENT_STORE.with(|star_payment_store| {
+        let mut binding = star_payment_store.borrow_mut();
+        if let Some((key, mut payment)) = binding.iter().find(|(_, payment)| payment.charge_id == charge_id) {
+            payment.refunded_at = None;
+            binding.insert(key, payment);
+        }
+    })
+}
+
+/// The admin of the init argument, by username or by the id learned from
+/// it, is always an admin. Everybody else has the role stored for them,
+/// falling back to the username whitelist. `None` means the user is not
+/// allowed in.
+pub fn get_role(user_id: i64, username: &str) -> Option<Role> {
+    if is_admin(username.to_string()) {
+        if get_admin_id().is_none() {
+            ADMIN_ID_STORE.with(|admin_id_store| set_config_cell(admin_id_store, Some(user_id as u64)));
+        }
+        return Some(Role::Admin);
+    }
+    if get_admin_id() == Some(user_id) {
+        return Some(Role::Admin);
+    }
+    if let Some(role) = ROLE_STORE.with(|role_store| role_store.borrow().get(&(user_id as u64))) {
+        return Some(role);
+    }
+    if is_user(username.to_string()) {
+        Some(Role::User)
+    } else {
+        None
+    }
+}
+
+/// Whether the bot answers the user at all.
+pub fn is_allowed(user_id: i64, username: &str) -> bool {
+    get_role(user_id, username).is_some_and(|role| role != Role::Banned)
+}
+
+pub fn is_premium(user_id: i64) -> bool {
+    let role = ROLE_STORE.with(|role_store| role_store.borrow().get(&(user_id as u64)));
+    role == Some(Role::Premium) || premium_until(user_id).is_some()
+}
+
+/// Gives the user a role, or takes it away with `None`, and writes it to
+/// the audit log.
+pub fn set_role(user_id: i64, role: Option<Role>, by: String) {
+    ROLE_STORE.with(|role_store| {
+        let mut binding = role_store.borrow_mut();
+        match role {
+            Some(role) => binding.insert(user_id as u64, role),
+            None => binding.remove(&(user_id as u64)),
+        };
+    });
+    ROLE_AUDIT_STORE.with(|role_audit_store| {
+        let mut binding = role_audit_store.borrow_mut();
+        let index = binding.len();
+        binding.insert(
+            index,
+            RoleChange {
+                user_id,
+                role,
+                by,
+                time: ic_cdk::api::time(),
+            },
+        );
+    });
+}
+
+pub fn list_roles() -> Vec<UserRole> {
+    ROLE_STORE.with(|role_store| {
+        role_store
+            .borrow()
+            .iter()
+            .map(|(user_id, role)| UserRole {
+                user_id: user_id as i64,
+                role,
+            })
+            .collect()
+    })
+}
+
+/// The latest `limit` role changes, oldest first.
+pub fn role_audit_log(limit: u64) -> Vec<RoleChange> {
+    ROLE_AUDIT_STORE.with(|role_audit_store| {
+        let binding = role_audit_store.borrow();
+        let start = binding.len().saturating_sub(limit);
+        binding.range(start..).map(|(_, change)| change).collect()
+    })
+}
+
+/// Who gets access requests: the admin of the init argument, once their id
+/// is known, then the users with the admin or moderator role.
+pub fn access_reviewer_ids() -> Vec<i64> {
+    let admin_id = get_admin_id();
+    admin_id
+        .into_iter()
+        .chain(
+            list_roles()
+                .into_iter()
+                .filter(|user_role| user_role.role >= Role::Moderator && Some(user_role.user_id) != admin_id)
+                .map(|user_role| user_role.user_id),
+        )
+        .collect()
+}
+
+/// Files a request unless one is pending or was denied within a day.
+/// Returns whether admins should be asked.
+pub fn add_access_request(user_id: i64, username: String) -> bool {
+    let time = ic_cdk::api::time();
+    ACCESS_REQUEST_STORE.with(|access_request_store| {
+        let mut binding = access_request_store.borrow_mut();
+        if let Some(request) = binding.get(&(user_id as u64)) {
+            match request.denied_at {
+                Some(denied_at) if denied_at + DAY > time => return false,
+                None => return false,
+                _ => {}
+            }
+        }
+        binding.insert(
+            user_id as u64,
+            AccessRequest {
+                username,
+                denied_at: None,
+            },
+        );
+        true
+    })
+}
+
+/// Decides a pending request. Returns the requester's username, or `None`
+/// if there is no pending request, e.g. because another admin was faster.
+pub fn decide_access_request(user_id: i64, approved: bool) -> Option<String> {
+    ACCESS_REQUEST_STORE.with(|access_request_store| {
+        let mut binding = access_request_store.borrow_mut();
+        let mut request = binding.get(&(user_id as u64)).filter(|request| request.denied_at.is_none())?;
+        let username = request.username.clone();
+        if approved {
+            binding.remove(&(user_id as u64));
+        } else {
+            request.denied_at = Some(ic_cdk::api::time());
+            binding.insert(user_id as u64, request);
+        }
+        Some(username)
+    })
+}
+
+pub fn add_invite(code: [u8; 8], created_by: i64) {
+    INVITE_STORE.with(|invite_store| {
+        invite_store
+            .borrow_mut()
+            .insert(code, (created_by as u64, ic_cdk::api::time()));
+    });
+}
+
+/// Invite codes can be used once.
+pub fn take_invite(code: [u8; 8]) -> bool {
+    INVITE_STORE.with(|invite_store| invite_store.borrow_mut().remove(&code).is_some())
+}
+
+pub fn get_bot_config() -> BotConfig {
+    let Config { model, prompt } = CONFIG_STORE.with(|config_store| config_store.borrow().get().clone());
+    BotConfig {
+        model,
+        prompt,
+        admin: get_admin(),
+        usernames: get_usernames(),
+        shortcuts: PROMPT_STORE.with(|prompt_store| {
+            prompt_store
+                .borrow()
+                .iter()
+                .map(|(shortcut, prompt)| Shortcut { shortcut, prompt })
+                .collect()
+        }),
+    }
+}
+
+pub fn set_bot_config(config: BotConfig) {
+    let model_config = Config {
+        model: config.model,
+        prompt: config.prompt,
+    };
+    CONFIG_STORE.with(|config_store| set_config_cell(config_store, model_config));
+    set_admin(config.admin);
+    set_usernames(config.usernames);
     PROMPT_STORE.with(|prompt_store| {
-        prompt_store.borrow().clone()
+        let mut binding = prompt_store.borrow_mut();
+        let old: Vec<String> = binding.iter().map(|(shortcut, _)| shortcut).collect();
+        for shortcut in old {
+            binding.remove(&shortcut);
+        }
+    });
+    add_shortcuts(config.shortcuts);
+}
+
+/// Users with stored messages, the most recently active first.
+pub fn list_users() -> Vec<UserSummary> {
+    let mut users: BTreeMap<i64, UserSummary> = BTreeMap::new();
+    USER_DATA_STORE.with(|user_data_store| {
+        for message in user_data_store.borrow().values() {
+            let user = users.entry(message.user_id).or_insert_with(|| UserSummary {
+                user_id: message.user_id,
+                username: message.username.clone(),
+                messages: 0,
+                last_message: 0,
+            });
+            user.messages += 1;
+            if message.date >= user.last_message {
+                user.last_message = message.date;
+                user.username = message.username.clone();
+            }
+        }
+    });
+    let mut users: Vec<UserSummary> = users.into_values().collect();
+    users.sort_by_key(|user| std::cmp::Reverse(user.last_message));
+    users
+}
+
+/// The user's latest `limit` messages in all chats, oldest first.
+pub fn user_history(user_id: i64, limit: usize) -> Vec<Message> {
+    let mut messages: Vec<Message> = USER_DATA_STORE.with(|user_data_store| {
+        user_data_store
+            .borrow()
+            .values()
+            .filter(|message| message.user_id == user_id)
+            .cloned()
+            .collect()
+    });
+    messages.sort_by_key(|message| message.date);
+    messages.split_off(messages.len().saturating_sub(limit))
+}
+
+/// Deletes the user's messages, or everybody's with `None`. Returns how
+/// many were deleted.
+pub fn clear_history(user_id: Option<i64>) -> u64 {
+    USER_DATA_STORE.with(|user_data_store| {
+        let mut binding = user_data_store.borrow_mut();
+        let before = binding.len();
+        binding.retain(|_, message| user_id.is_some_and(|user_id| message.user_id != user_id));
+        (before - binding.len()) as u64
+    })
+}
+
+/// Users, conversations and messages in the message store.
+pub fn message_counts() -> (u64, u64, u64) {
+    USER_DATA_STORE.with(|user_data_store| {
+        let binding = user_data_store.borrow();
+        let users: BTreeSet<i64> = binding.values().map(|message| message.user_id).collect();
+        let conversations: BTreeSet<Conversation> = binding.values().map(|message| message.conversation).collect();
+        (users.len() as u64, conversations.len() as u64, binding.len() as u64)
     })
-}
\ No newline at end of file
+}
+
+/// Deletes everything kept about the user except what pays for or limits
+/// them: credits, Stars receipts, their role and tier, and today's usage,
+/// which the daily quota is counted against. Returns how many messages were
+/// deleted.
+pub fn forget_user(user_id: i64) -> u64 {
+    let messages = clear_history(Some(user_id));
+    let today = period_index(UsagePeriod::Day, ic_cdk::api::time());
+    USAGE_STORE.with(|usage_store| {
+        let mut binding = usage_store.borrow_mut();
+        let keys: Vec<UsageKey> = binding
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| key.subject == UsageSubject::User && key.id == user_id)
+            .filter(|key| !(key.period == UsagePeriod::Day && key.index == today))
+            .collect();
+        for key in keys {
+            binding.remove(&key);
+        }
+    });
+    RESPONSE_CACHE_STORE.with(|response_cache_store| {
+        let mut binding = response_cache_store.borrow_mut();
+        let keys: Vec<[u8; 32]> = binding
+            .iter()
+            .filter(|(_, cached)| cached.user_id == Some(user_id))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            binding.remove(&key);
+        }
+    });
+    INLINE_CACHE_STORE.with(|inline_cache_store| {
+        inline_cache_store.borrow_mut().retain(|_, (_, id, _)| *id != user_id);
+    });
+    INCOGNITO_BUFFER_STORE.with(|incognito_buffer_store| {
+        incognito_buffer_store.borrow_mut().retain(|(_, id), _| *id != user_id);
+    });
+    ACCESS_REQUEST_STORE.with(|access_request_store| access_request_store.borrow_mut().remove(&(user_id as u64)));
+    DEPOSIT_WATCH_STORE.with(|deposit_watch_store| deposit_watch_store.borrow_mut().remove(&user_id));
+    messages
+}
+
+pub fn get_retention() -> RetentionConfig {
+    RETENTION_CONFIG_STORE.with(|retention_config_store| retention_config_store.borrow().get().clone())
+}
+
+pub fn set_retention(config: RetentionConfig) {
+    RETENTION_CONFIG_STORE.with(|retention_config_store| set_config_cell(retention_config_store, config));
+}
+
+pub fn get_retention_stats() -> RetentionStats {
+    RETENTION_STATS_STORE.with(|retention_stats_store| retention_stats_store.borrow().clone())
+}
+
+pub fn update_retention_stats(update: impl FnOnce(&mut RetentionStats)) {
+    RETENTION_STATS_STORE.with(|retention_stats_store| update(&mut retention_stats_store.borrow_mut()));
+}
+
+/// Roughly what a message takes on the heap.
+pub fn message_bytes(key: &str, message: &Message) -> u64 {
+    (64 + key.len() + message.username.len() + message.question.len() + message.answer.len() + message.model.len())
+        as u64
+}
+
+/// Walks the messages from `cursor` on in key order and deletes those
+/// `delete` picks, until the call has used `instruction_limit` instructions.
+/// Returns the first key not visited, or `None` at the end of the store.
+pub fn sweep_messages(
+    cursor: Option<String>,
+    instruction_limit: u64,
+    mut delete: impl FnMut(&str, &Message) -> bool,
+) -> Option<String> {
+    let mut doomed = vec![];
+    let next = USER_DATA_STORE.with(|user_data_store| {
+        let binding = user_data_store.borrow();
+        let range = match &cursor {
+            Some(cursor) => binding.range::<String, _>((Bound::Included(cursor), Bound::Unbounded)),
+            None => binding.range::<String, _>(..),
+        };
+        for (key, message) in range {
+            if ic_cdk::api::instruction_counter() > instruction_limit {
+                return Some(key.clone());
+            }
+            if delete(key, message) {
+                doomed.push(key.clone());
+            }
+        }
+        None
+    });
+    USER_DATA_STORE.with(|user_data_store| {
+        let mut binding = user_data_store.borrow_mut();
+        for key in doomed {
+            binding.remove(&key);
+        }
+    });
+    next
+}
+
+pub fn is_incognito(user_id: i64) -> bool {
+    INCOGNITO_STORE.with(|incognito_store| incognito_store.borrow().contains_key(&(user_id as u64)))
+}
+
+/// Switching either way drops what was buffered.
+pub fn set_incognito(user_id: i64, incognito: bool) {
+    INCOGNITO_STORE.with(|incognito_store| {
+        let mut binding = incognito_store.borrow_mut();
+        if incognito {
+            binding.insert(user_id as u64, ());
+        } else {
+            binding.remove(&(user_id as u64));
+        }
+    });
+    INCOGNITO_BUFFER_STORE.with(|incognito_buffer_store| {
+        incognito_buffer_store.borrow_mut().retain(|(_, id), _| *id != user_id);
+    });
+}
+
+/// The incognito user's current thread, unless it expired.
+pub fn get_incognito_messages(conversation: Conversation, user_id: i64) -> Vec<Message> {
+    let time = ic_cdk::api::time();
+    INCOGNITO_BUFFER_STORE.with(|incognito_buffer_store| {
+        let mut binding = incognito_buffer_store.borrow_mut();
+        binding.retain(|_, (updated, _)| time - *updated < INCOGNITO_TTL);
+        binding
+            .get(&(conversation, user_id))
+            .map(|(_, messages)| messages.clone())
+            .unwrap_or_default()
+    })
+}
+
+/// Like `add_new_messages`, in memory only. A retry replaces the latest
+/// turn.
+pub fn add_incognito_message(conversation: Conversation, user_id: i64, message: Message, replaces_latest: bool) {
+    let time = ic_cdk::api::time();
+    let mut messages = get_incognito_messages(conversation, user_id);
+    if replaces_latest {
+        messages.pop();
+    }
+    if !message.is_follow {
+        messages.clear();
+    }
+    messages.push(message);
+    INCOGNITO_BUFFER_STORE.with(|incognito_buffer_store| {
+        incognito_buffer_store
+            .borrow_mut()
+            .insert((conversation, user_id), (time, messages));
+    });
+}
+
+pub fn clear_incognito_messages(conversation: Conversation, user_id: i64) {
+    INCOGNITO_BUFFER_STORE.with(|incognito_buffer_store| {
+        incognito_buffer_store.borrow_mut().remove(&(conversation, user_id));
+    });
+}
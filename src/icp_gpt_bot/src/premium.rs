@@ -0,0 +1,169 @@
+use serde_json::json;
+use telegram_bot_raw::Message as TelegramMessage;
+
+use telegram_bot_raw::MessageChat;
+
+use crate::bot::{send_message, webhook_reply};
+use crate::memory::{clear_refund, format_time, get_premium_config, mark_refunded, premium_until, record_star_payment, star_payments};
+use crate::telegram::request_telegram;
+use crate::types::{Conversation, HttpResponse, PreCheckoutQuery, RefundedPayment, StarPayment, SuccessfulPayment};
+
+/// Telegram Stars.
+const CURRENCY: &str = "XTR";
+
+/// Longest invoice description Telegram accepts.
+const MAX_DESCRIPTION: usize = 255;
+
+const DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// What Telegram tells every replica but the first one to ask for a refund.
+const ALREADY_REFUNDED: &str = "CHARGE_ALREADY_REFUNDED";
+
+/// The invoice payload carries what was offered, so a payment made after
+/// the admin changed the offer can be told apart.
+fn invoice_payload(period_days: u32, price_stars: u32) -> String {
+    format!("premium:{}:{}", period_days, price_stars)
+}
+
+fn parse_payload(payload: &str) -> Option<(u32, u32)> {
+    let mut parts = payload.strip_prefix("premium:")?.split(':');
+    let period_days = parts.next()?.parse().ok()?;
+    let price_stars = parts.next()?.parse().ok()?;
+    Some((period_days, price_stars))
+}
+
+/// Answers `/premium` with the invoice itself, which tells the user where
+/// they stand. An outcall would send one invoice per replica.
+pub fn premium_invoice(chat: MessageChat, conversation: Conversation, user_id: i64) -> HttpResponse {
+    let config = get_premium_config();
+    if !config.enabled {
+        return send_message(chat, conversation.thread_id, "Premium is not available.".to_string(), None);
+    }
+    let status = match premium_until(user_id) {
+        Some(until) => format!(
+            "You are premium until {}. Paying again adds {} days.",
+            format_time(until),
+            config.period_days
+        ),
+        None => format!("{} days of premium.", config.period_days),
+    };
+    let description: String = format!("{}\n\n{}", config.description, status)
+        .chars()
+        .take(MAX_DESCRIPTION)
+        .collect();
+    let mut params = json!({
+        "chat_id": conversation.chat_id,
+        "title": config.title,
+        "description": description.trim(),
+        "payload": invoice_payload(config.period_days, config.price_stars),
+        "currency": CURRENCY,
+        "prices": [{ "label": config.title, "amount": config.price_stars }],
+    });
+    if conversation.thread_id != 0 {
+        params["message_thread_id"] = json!(conversation.thread_id);
+    }
+    webhook_reply("sendInvoice", params)
+}
+
+/// Approves the payment only if it matches the current offer.
+pub fn answer_pre_checkout(query: PreCheckoutQuery) -> HttpResponse {
+    let config = get_premium_config();
+    let offer = invoice_payload(config.period_days, config.price_stars);
+    let error = if !config.enabled {
+        Some("Premium is not available any more.")
+    } else if query.currency != CURRENCY || query.total_amount != config.price_stars || query.invoice_payload != offer {
+        Some("This invoice is outdated, please send /premium again.")
+    } else {
+        None
+    };
+    ic_cdk::println!("Pre-checkout by {}: {}", query.from.id, error.unwrap_or("ok"));
+    let mut params = json!({
+        "pre_checkout_query_id": query.id,
+        "ok": error.is_none(),
+    });
+    if let Some(error) = error {
+        params["error_message"] = json!(error);
+    }
+    webhook_reply("answerPreCheckoutQuery", params)
+}
+
+/// Records the receipt and extends the user's premium period, once per
+/// charge: Telegram sends the update again when our answer got lost.
+pub fn handle_successful_payment(message: TelegramMessage, payment: SuccessfulPayment, thread_id: i64) -> HttpResponse {
+    let user_id: i64 = message.from.id.into();
+    let charge_id = payment.telegram_payment_charge_id.clone();
+    if let Some(recorded) = star_payments(Some(user_id))
+        .into_iter()
+        .find(|recorded| recorded.charge_id == charge_id)
+    {
+        ic_cdk::println!("Payment {} of {} already recorded", charge_id, user_id);
+        return thank_you(message.chat, thread_id, recorded.premium_until, &charge_id);
+    }
+    let time = ic_cdk::api::time();
+    let period_days = parse_payload(&payment.invoice_payload)
+        .map(|(period_days, _)| period_days)
+        .unwrap_or_else(|| get_premium_config().period_days);
+    let start = premium_until(user_id).unwrap_or(time);
+    let until = start + period_days as u64 * DAY;
+    record_star_payment(StarPayment {
+        user_id,
+        stars: payment.total_amount,
+        charge_id: payment.telegram_payment_charge_id.clone(),
+        paid_at: time,
+        premium_until: until,
+        refunded_at: None,
+    });
+    ic_cdk::println!(
+        "Payment of {} {} by {}: {}",
+        payment.total_amount,
+        payment.currency,
+        user_id,
+        payment.telegram_payment_charge_id
+    );
+    thank_you(message.chat, thread_id, until, &charge_id)
+}
+
+fn thank_you(chat: MessageChat, thread_id: i64, until: u64, charge_id: &str) -> HttpResponse {
+    send_message(
+        chat,
+        thread_id,
+        format!("Thank you! You are premium until {}.\nReceipt: {}", format_time(until), charge_id),
+        None,
+    )
+}
+
+/// Refunds made outside of the bot, e.g. by Telegram support.
+pub fn handle_refunded_payment(refund: RefundedPayment) {
+    match mark_refunded(&refund.telegram_payment_charge_id) {
+        Some(payment) => ic_cdk::println!("Payment {} of {} refunded", payment.charge_id, payment.user_id),
+        None => ic_cdk::println!("Refund of unknown payment {}", refund.telegram_payment_charge_id),
+    }
+}
+
+/// Gives the Stars back and ends the premium period the payment bought.
+/// Every replica sends the refund, so the payment is marked first and the
+/// mark only undone when Telegram turns the refund down; the
+/// `refunded_payment` update has the final word.
+pub async fn refund_payment(user_id: i64, charge_id: String) -> Result<StarPayment, String> {
+    let payment = star_payments(Some(user_id))
+        .into_iter()
+        .find(|payment| payment.charge_id == charge_id)
+        .ok_or("No such payment.")?;
+    if payment.refunded_at.is_some() {
+        return Err("The payment was already refunded.".to_string());
+    }
+    let payment = mark_refunded(&charge_id).ok_or("No such payment.")?;
+    let answer = request_telegram(
+        "refundStarPayment",
+        json!({ "user_id": user_id, "telegram_payment_charge_id": charge_id }),
+    )
+    .await
+    .map_err(|err| format!("{} The payment stays marked as refunded, as the refund may have gone through.", err))?;
+    let description = answer["description"].as_str().unwrap_or("Unknown error");
+    if answer["ok"].as_bool() == Some(true) || description.contains(ALREADY_REFUNDED) {
+        Ok(payment)
+    } else {
+        clear_refund(&charge_id);
+        Err(description.to_string())
+    }
+}
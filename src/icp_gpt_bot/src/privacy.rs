@@ -0,0 +1,154 @@
+use serde_json::json;
+use telegram_bot_raw::MessageChat;
+
+use crate::memory::{
+    forget_user, format_time, get_credits, get_role, get_usage, star_payments, user_history,
+};
+use crate::bot::{documents_reply, escape_html, send_message};
+use crate::types::{Conversation, HttpResponse, Message, MessageType, UsagePeriod, UsageSubject};
+
+const HISTORY_TURNS: usize = 10;
+const HISTORY_PREVIEW_CHARS: usize = 300;
+/// Leaves room in the 2 MB a canister reply may take.
+const MAX_EXPORT_BYTES: usize = 1_800_000;
+
+fn preview(text: &str) -> String {
+    let mut preview: String = text.chars().take(HISTORY_PREVIEW_CHARS).collect();
+    if preview.len() < text.len() {
+        preview.push('…');
+    }
+    escape_html(&preview)
+}
+
+/// The user's latest turns in this chat; other chats are not shown, so
+/// nothing leaks into groups.
+pub fn history_text(conversation: Conversation, user_id: i64) -> String {
+    let messages: Vec<Message> = user_history(user_id, usize::MAX)
+        .into_iter()
+        .filter(|message| message.conversation == conversation)
+        .collect();
+    if messages.is_empty() {
+        return "'Nothing is stored about you in this chat.'".to_string();
+    }
+    let turns: Vec<String> = messages[messages.len().saturating_sub(HISTORY_TURNS)..]
+        .iter()
+        .map(|message| {
+            format!(
+                "<b>{}</b>\nYou: {}\nBot: {}",
+                format_time(message.date),
+                preview(&message.question),
+                preview(&message.answer)
+            )
+        })
+        .collect();
+    format!(
+        "'Your last {} of {} stored turns here:\n\n{}'",
+        turns.len(),
+        messages.len(),
+        turns.join("\n\n")
+    )
+}
+
+pub fn forget_prompt_text() -> String {
+    "'This deletes your conversations and usage history in all chats, for good. Credits, Stars receipts and today's usage are kept. Are you sure?'".to_string()
+}
+
+pub fn forget_text(user_id: i64) -> String {
+    let messages = forget_user(user_id);
+    ic_cdk::println!("Forgot user {}", user_id);
+    format!("Done. {} stored messages were deleted.", messages)
+}
+
+fn export_json(user_id: i64, messages: &[Message]) -> String {
+    let messages: Vec<_> = messages
+        .iter()
+        .map(|message| {
+            json!({
+                "chat_id": message.conversation.chat_id,
+                "thread_id": message.conversation.thread_id,
+                "time": format_time(message.date),
+                "type": match message.types {
+                    MessageType::Chat => "chat",
+                    MessageType::Image => "image",
+                },
+                "model": message.model,
+                "question": message.question,
+                "answer": message.answer,
+                "prompt_tokens": message.usage.prompt_tokens,
+                "completion_tokens": message.usage.completion_tokens,
+            })
+        })
+        .collect();
+    let payments: Vec<_> = star_payments(Some(user_id))
+        .into_iter()
+        .map(|payment| {
+            json!({
+                "charge_id": payment.charge_id,
+                "stars": payment.stars,
+                "paid_at": format_time(payment.paid_at),
+                "premium_until": format_time(payment.premium_until),
+                "refunded_at": payment.refunded_at.map(format_time),
+            })
+        })
+        .collect();
+    let usage = |period| {
+        let usage = get_usage(UsageSubject::User, user_id, period);
+        json!({
+            "requests": usage.requests,
+            "images": usage.images,
+            "prompt_tokens": usage.prompt_tokens,
+            "completion_tokens": usage.completion_tokens,
+        })
+    };
+    let export = json!({
+        "user_id": user_id,
+        "exported_at": format_time(ic_cdk::api::time()),
+        "role": get_role(user_id, "").map(|role| role.name()),
+        "credits": get_credits(user_id).to_string(),
+        "usage_today": usage(UsagePeriod::Day),
+        "usage_this_month": usage(UsagePeriod::Month),
+        "star_payments": payments,
+        "messages": messages,
+    });
+    serde_json::to_string_pretty(&export).unwrap_or_default()
+}
+
+fn export_markdown(user_id: i64, messages: &[Message]) -> String {
+    let mut markdown = format!(
+        "# Conversations of {}\n\nExported {}.\n",
+        user_id,
+        format_time(ic_cdk::api::time())
+    );
+    for message in messages {
+        markdown.push_str(&format!(
+            "\n## {} (chat {})\n\n**You:** {}\n\n**Bot ({}):** {}\n",
+            format_time(message.date),
+            message.conversation.chat_id,
+            message.question,
+            message.model,
+            message.answer
+        ));
+    }
+    markdown
+}
+
+/// Answers `/export` with everything stored about the user, as JSON and
+/// as Markdown. Only in the private chat with the user, as the files are
+/// the webhook reply to the chat asking.
+pub fn export_documents(chat: MessageChat, conversation: Conversation, user_id: i64) -> HttpResponse {
+    let reply = |text: &str| send_message(chat.clone(), conversation.thread_id, text.to_string(), None);
+    if conversation.chat_id != user_id {
+        return reply("Send /export in our private chat, so your data stays between us.");
+    }
+    let messages = user_history(user_id, usize::MAX);
+    let json = export_json(user_id, &messages);
+    let markdown = export_markdown(user_id, &messages);
+    if json.len() + markdown.len() > MAX_EXPORT_BYTES {
+        return reply("Your export is too large to send. Ask the admin of the bot for it.");
+    }
+    documents_reply(
+        user_id,
+        vec![("export.json", json.into_bytes()), ("export.md", markdown.into_bytes())],
+        "Everything stored about you, as JSON, and your conversations as Markdown",
+    )
+}
@@ -0,0 +1,169 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use ic_cdk_timers::TimerId;
+
+use crate::memory::{get_retention, message_bytes, sweep_messages, update_retention_stats};
+use crate::types::RetentionConfig;
+
+const DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Instructions one timer call may spend; the limit for a call is 40B.
+const TICK_INSTRUCTIONS: u64 = 5_000_000_000;
+
+enum Phase {
+    /// Deletes expired messages and takes note of the rest.
+    Age,
+    /// Deletes what is over the per-user and total limits.
+    Trim,
+}
+
+/// A sweep in progress: two passes over the message store, each of which
+/// may take several timer calls.
+struct Sweep {
+    config: RetentionConfig,
+    time: u64,
+    phase: Phase,
+    cursor: Option<String>,
+    /// Dates of every user's messages, for the per-user limit.
+    user_dates: BTreeMap<i64, Vec<u64>>,
+    /// Date, size and user of every message, for the total limit.
+    sizes: Vec<(u64, u64, i64)>,
+    /// Messages of the user older than this are deleted.
+    user_cutoffs: BTreeMap<i64, u64>,
+    /// Messages this old or older are deleted.
+    size_cutoff: Option<u64>,
+}
+
+thread_local! {
+    static SWEEP: RefCell<Option<Sweep>> = const { RefCell::new(None) };
+
+    static SWEEP_TIMER: RefCell<Option<TimerId>> = const { RefCell::new(None) };
+}
+
+/// Timers do not survive upgrades, so this runs from `init` and
+/// `post_upgrade`, and again when the interval changes.
+pub fn start_retention_sweeper() {
+    let interval = Duration::from_secs(get_retention().sweep_interval_secs.max(60));
+    let timer = ic_cdk_timers::set_timer_interval(interval, sweep_tick);
+    if let Some(previous) = SWEEP_TIMER.with(|sweep_timer| sweep_timer.borrow_mut().replace(timer)) {
+        ic_cdk_timers::clear_timer(previous);
+    }
+}
+
+/// Starts a sweep unless one is still going, and works on it until the
+/// instruction budget of this call is used up; then it goes on right away
+/// in another call.
+fn sweep_tick() {
+    let mut sweep = SWEEP.with(|sweep| sweep.borrow_mut().take()).unwrap_or_else(|| {
+        update_retention_stats(|stats| stats.in_progress = true);
+        Sweep {
+            config: get_retention(),
+            time: ic_cdk::api::time(),
+            phase: Phase::Age,
+            cursor: None,
+            user_dates: BTreeMap::new(),
+            sizes: vec![],
+            user_cutoffs: BTreeMap::new(),
+            size_cutoff: None,
+        }
+    });
+    update_retention_stats(|stats| stats.ticks += 1);
+    let limit = ic_cdk::api::instruction_counter() + TICK_INSTRUCTIONS;
+    loop {
+        let done = match sweep.phase {
+            Phase::Age => age_pass(&mut sweep, limit),
+            Phase::Trim => trim_pass(&mut sweep, limit),
+        };
+        if !done {
+            SWEEP.with(|current| *current.borrow_mut() = Some(sweep));
+            ic_cdk_timers::set_timer(Duration::ZERO, sweep_tick);
+            return;
+        }
+        match sweep.phase {
+            Phase::Age if sweep.config.max_messages_per_user.is_some() || sweep.config.max_total_bytes.is_some() => {
+                compute_cutoffs(&mut sweep);
+                sweep.phase = Phase::Trim;
+            }
+            _ => break,
+        }
+    }
+    update_retention_stats(|stats| {
+        stats.sweeps += 1;
+        stats.in_progress = false;
+        stats.last_sweep_finished = Some(ic_cdk::api::time());
+    });
+}
+
+/// Returns whether the pass reached the end of the store.
+fn age_pass(sweep: &mut Sweep, limit: u64) -> bool {
+    let max_age = sweep.config.max_age_days.map(|days| days as u64 * DAY);
+    let track_users = sweep.config.max_messages_per_user.is_some();
+    let track_sizes = sweep.config.max_total_bytes.is_some();
+    let mut deleted = 0;
+    let cursor = sweep.cursor.take();
+    sweep.cursor = sweep_messages(cursor, limit, |key, message| {
+        if max_age.is_some_and(|max_age| sweep.time.saturating_sub(message.date) > max_age) {
+            deleted += 1;
+            return true;
+        }
+        if track_users {
+            sweep.user_dates.entry(message.user_id).or_default().push(message.date);
+        }
+        if track_sizes {
+            sweep.sizes.push((message.date, message_bytes(key, message), message.user_id));
+        }
+        false
+    });
+    update_retention_stats(|stats| stats.deleted_by_age += deleted);
+    sweep.cursor.is_none()
+}
+
+/// Works out which dates to cut at, from what the age pass saw.
+fn compute_cutoffs(sweep: &mut Sweep) {
+    if let Some(max) = sweep.config.max_messages_per_user {
+        for (user_id, dates) in &mut sweep.user_dates {
+            if dates.len() > max as usize {
+                dates.sort_unstable();
+                sweep.user_cutoffs.insert(*user_id, dates[dates.len() - max as usize]);
+            }
+        }
+    }
+    if let Some(max) = sweep.config.max_total_bytes {
+        let user_cutoffs = &sweep.user_cutoffs;
+        sweep
+            .sizes
+            .retain(|(date, _, user_id)| user_cutoffs.get(user_id).is_none_or(|cutoff| date >= cutoff));
+        // newest first, until the limit is reached
+        sweep.sizes.sort_unstable_by_key(|(date, _, _)| std::cmp::Reverse(*date));
+        let mut total = 0;
+        sweep.size_cutoff = sweep.sizes.iter().find_map(|(date, bytes, _)| {
+            total += bytes;
+            (total > max).then_some(*date)
+        });
+    }
+    sweep.user_dates.clear();
+    sweep.sizes.clear();
+}
+
+fn trim_pass(sweep: &mut Sweep, limit: u64) -> bool {
+    let (mut by_count, mut by_size) = (0, 0);
+    let cursor = sweep.cursor.take();
+    sweep.cursor = sweep_messages(cursor, limit, |_, message| {
+        if sweep.user_cutoffs.get(&message.user_id).is_some_and(|cutoff| message.date < *cutoff) {
+            by_count += 1;
+            true
+        } else if sweep.size_cutoff.is_some_and(|cutoff| message.date <= cutoff) {
+            by_size += 1;
+            true
+        } else {
+            false
+        }
+    });
+    update_retention_stats(|stats| {
+        stats.deleted_by_count += by_count;
+        stats.deleted_by_size += by_size;
+    });
+    sweep.cursor.is_none()
+}
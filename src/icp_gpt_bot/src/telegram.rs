@@ -0,0 +1,292 @@
+use ic_cdk::api::management_canister::http_request::{CanisterHttpRequestArgument, HttpHeader, HttpMethod};
+use ic_cdk::api::management_canister::main::raw_rand;
+use hmac::{Hmac, Mac};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use telegram_bot_raw::{CallbackQueryId, InlineKeyboardButton, InlineKeyboardMarkup};
+
+use crate::bot::webhook_reply;
+use crate::cycles::outcall;
+use crate::memory::{get_bot_username, get_secret, get_token, set_bot_username, set_secret, set_webhook_secret};
+use crate::transform::TransformKind;
+use crate::types::HttpResponse;
+
+/// Actions behind the inline buttons attached to every answer.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum CallbackAction {
+    Retry,
+    Continue,
+    NewChat,
+    Imagine,
+    /// Sent by an unknown user; the user id is the requester's.
+    RequestAccess,
+    /// Pressed by an admin; the user id is the requester's.
+    Approve,
+    Deny,
+    /// Confirms `/forget`.
+    Forget,
+    KeepData,
+}
+
+impl CallbackAction {
+    fn code(&self) -> char {
+        match self {
+            CallbackAction::Retry => 'r',
+            CallbackAction::Continue => 'c',
+            CallbackAction::NewChat => 'n',
+            CallbackAction::Imagine => 'i',
+            CallbackAction::RequestAccess => 'q',
+            CallbackAction::Approve => 'a',
+            CallbackAction::Deny => 'd',
+            CallbackAction::Forget => 'f',
+            CallbackAction::KeepData => 'k',
+        }
+    }
+
+    fn from_code(code: char) -> Option<Self> {
+        match code {
+            'r' => Some(CallbackAction::Retry),
+            'c' => Some(CallbackAction::Continue),
+            'n' => Some(CallbackAction::NewChat),
+            'i' => Some(CallbackAction::Imagine),
+            'q' => Some(CallbackAction::RequestAccess),
+            'a' => Some(CallbackAction::Approve),
+            'd' => Some(CallbackAction::Deny),
+            'f' => Some(CallbackAction::Forget),
+            'k' => Some(CallbackAction::KeepData),
+            _ => None,
+        }
+    }
+}
+
+/// Calls a Bot API method through an HTTPS outcall, for everything that
+/// cannot be returned as the webhook response.
+pub async fn call_telegram(method: &str, params: Value) -> Result<Value, String> {
+    let value = request_telegram(method, params).await?;
+    if value["ok"].as_bool() == Some(true) {
+        Ok(value)
+    } else {
+        Err(value["description"].as_str().unwrap_or("Unknown error").to_string())
+    }
+}
+
+/// Like `call_telegram`, but returns Telegram's answer whether it is `ok`
+/// or not. An error means there was no answer, so the call may or may not
+/// have gone through.
+pub async fn request_telegram(method: &str, params: Value) -> Result<Value, String> {
+    post_telegram(method, "application/json".to_string(), params.to_string().into_bytes()).await
+}
+
+async fn post_telegram(method: &str, content_type: String, body: Vec<u8>) -> Result<Value, String> {
+    let token = get_token();
+    if token.is_empty() {
+        return Err("Bot token is not configured.".to_string());
+    }
+    let request = CanisterHttpRequestArgument {
+        url: format!("https://api.telegram.org/bot{}/{}", token, method),
+        method: HttpMethod::POST,
+        headers: vec![HttpHeader {
+            name: "Content-Type".to_string(),
+            value: content_type,
+        }],
+        body: Some(body),
+        max_response_bytes: Some(10_000),
+        transform: Some(TransformKind::Telegram.context()),
+    };
+
+    match outcall(request).await.0 {
+        Ok(response) => serde_json::from_slice::<Value>(&response.body)
+            .map_err(|err| format!("Failed to parse response: {}", err)),
+        Err((r, m)) => Err(format!("HTTP request failed with code {:?}: {}", r, m)),
+    }
+}
+
+fn callback_answer(callback_query_id: CallbackQueryId, text: Option<String>) -> Value {
+    let mut params = json!({ "callback_query_id": callback_query_id });
+    if let Some(text) = text {
+        params["text"] = Value::String(text);
+    }
+    params
+}
+
+/// For callbacks whose webhook reply is taken by another message.
+pub async fn answer_callback_query(callback_query_id: CallbackQueryId, text: Option<String>) {
+    if let Err(err) = call_telegram("answerCallbackQuery", callback_answer(callback_query_id, text)).await {
+        ic_cdk::println!("answerCallbackQuery failed - {}", err);
+    }
+}
+
+/// Answers the callback with the webhook reply, when nothing else is sent.
+pub fn callback_answer_reply(callback_query_id: CallbackQueryId, text: Option<String>) -> HttpResponse {
+    webhook_reply("answerCallbackQuery", callback_answer(callback_query_id, text))
+}
+
+/// The bot's own username, fetched once with `getMe`. It is needed to tell
+/// whether a group message is addressed to us.
+pub async fn bot_username() -> String {
+    let username = get_bot_username();
+    if !username.is_empty() {
+        return username;
+    }
+    match call_telegram("getMe", json!({})).await {
+        Ok(value) => {
+            let username = value["result"]["username"].as_str().unwrap_or_default().to_string();
+            set_bot_username(username.clone());
+            username
+        }
+        Err(err) => {
+            ic_cdk::println!("getMe failed - {}", err);
+            username
+        }
+    }
+}
+
+/// Points the webhook at a new random path, so a leaked URL stops working.
+/// Returns the new URL.
+pub async fn rotate_webhook() -> Result<String, String> {
+    let (bytes,) = raw_rand()
+        .await
+        .map_err(|(r, m)| format!("raw_rand failed with code {:?}: {}", r, m))?;
+    let secret = hex::encode(&bytes[..16]);
+    let url = format!("https://{}.raw.icp0.io/webhook/{}", ic_cdk::id(), secret);
+    call_telegram("setWebhook", json!({ "url": url })).await?;
+    set_webhook_secret(secret);
+    Ok(url)
+}
+
+pub async fn is_chat_admin(chat_id: i64, user_id: i64) -> bool {
+    if chat_id == user_id {
+        // private chat with the user
+        return true;
+    }
+    match call_telegram("getChatMember", json!({ "chat_id": chat_id, "user_id": user_id })).await {
+        Ok(value) => matches!(
+            value["result"]["status"].as_str(),
+            Some("creator") | Some("administrator")
+        ),
+        Err(err) => {
+            ic_cdk::println!("getChatMember failed - {}", err);
+            false
+        }
+    }
+}
+
+/// Makes sure the signing secret exists. It is drawn from the management
+/// canister's randomness the first time a button is needed.
+pub async fn ensure_secret() {
+    if !get_secret().is_empty() {
+        return;
+    }
+    match raw_rand().await {
+        Ok((bytes,)) => set_secret(bytes),
+        Err((r, m)) => ic_cdk::println!("raw_rand failed with code {:?}: {}", r, m),
+    }
+}
+
+fn signature(payload: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(&get_secret()).expect("HMAC takes keys of any size");
+    mac.update(payload.as_bytes());
+    hex::encode(&mac.finalize().into_bytes()[..8])
+}
+
+/// Callback data looks like `r:123456789:0011223344556677`: the action, the
+/// id of the user owning the thread, and a truncated HMAC-SHA256 of both.
+/// It stays well below Telegram's 64-byte limit.
+pub fn sign_callback(action: CallbackAction, user_id: i64) -> String {
+    let payload = format!("{}:{}", action.code(), user_id);
+    let signature = signature(&payload);
+    format!("{}:{}", payload, signature)
+}
+
+/// Returns the action and the user id it was signed for, if the signature
+/// matches.
+pub fn read_callback(data: &str) -> Option<(CallbackAction, i64)> {
+    let (payload, signature_part) = data.rsplit_once(':')?;
+    let (code, owner) = payload.split_once(':')?;
+    if get_secret().is_empty() || signature(payload) != signature_part {
+        return None;
+    }
+    let mut chars = code.chars();
+    match (chars.next(), chars.next()) {
+        (Some(code), None) => Some((CallbackAction::from_code(code)?, owner.parse().ok()?)),
+        _ => None,
+    }
+}
+
+pub fn access_request_keyboard(user_id: i64) -> InlineKeyboardMarkup {
+    let mut keyboard = InlineKeyboardMarkup::new();
+    keyboard.add_row(vec![InlineKeyboardButton::callback(
+        "🔑 Request access",
+        sign_callback(CallbackAction::RequestAccess, user_id),
+    )]);
+    keyboard
+}
+
+/// Sent to admins; `requester` is the user asking for access.
+pub fn access_decision_keyboard(requester: i64) -> InlineKeyboardMarkup {
+    let mut keyboard = InlineKeyboardMarkup::new();
+    keyboard.add_row(vec![
+        InlineKeyboardButton::callback("✅ Approve", sign_callback(CallbackAction::Approve, requester)),
+        InlineKeyboardButton::callback("❌ Deny", sign_callback(CallbackAction::Deny, requester)),
+    ]);
+    keyboard
+}
+
+pub fn forget_keyboard(user_id: i64) -> InlineKeyboardMarkup {
+    let mut keyboard = InlineKeyboardMarkup::new();
+    keyboard.add_row(vec![
+        InlineKeyboardButton::callback("🗑 Delete everything", sign_callback(CallbackAction::Forget, user_id)),
+        InlineKeyboardButton::callback("Cancel", sign_callback(CallbackAction::KeepData, user_id)),
+    ]);
+    keyboard
+}
+
+pub fn answer_keyboard(user_id: i64) -> InlineKeyboardMarkup {
+    let mut keyboard = InlineKeyboardMarkup::new();
+    keyboard.add_row(vec![
+        InlineKeyboardButton::callback("🔁 Retry", sign_callback(CallbackAction::Retry, user_id)),
+        InlineKeyboardButton::callback("➕ Continue", sign_callback(CallbackAction::Continue, user_id)),
+    ]);
+    keyboard.add_row(vec![
+        InlineKeyboardButton::callback("🆕 New chat", sign_callback(CallbackAction::NewChat, user_id)),
+        InlineKeyboardButton::callback("🖼 Imagine this", sign_callback(CallbackAction::Imagine, user_id)),
+    ]);
+    keyboard
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::memory::set_secret;
+
+    #[test]
+    fn signed_callbacks_are_read_back() {
+        set_secret(vec![7; 32]);
+        for action in [CallbackAction::Retry, CallbackAction::Imagine, CallbackAction::KeepData] {
+            let data = sign_callback(action, 123_456_789);
+            assert!(data.len() <= 64);
+            assert_eq!(read_callback(&data), Some((action, 123_456_789)));
+        }
+    }
+
+    #[test]
+    fn tampered_callbacks_are_rejected() {
+        set_secret(vec![7; 32]);
+        let data = sign_callback(CallbackAction::Retry, 42);
+        let (_, signature) = data.rsplit_once(':').unwrap();
+        assert_eq!(read_callback(&format!("r:43:{}", signature)), None);
+        assert_eq!(read_callback(&format!("n:42:{}", signature)), None);
+        assert_eq!(read_callback("r:42"), None);
+        assert_eq!(read_callback("garbage"), None);
+    }
+
+    #[test]
+    fn callbacks_need_a_secret() {
+        set_secret(vec![7; 32]);
+        let data = sign_callback(CallbackAction::Continue, 42);
+        set_secret(vec![]);
+        assert_eq!(read_callback(&data), None);
+        set_secret(vec![8; 32]);
+        assert_eq!(read_callback(&data), None);
+    }
+}
@@ -0,0 +1,520 @@
+use std::future::Future;
+use std::iter::Peekable;
+use std::pin::Pin;
+use std::str::Chars;
+
+use serde_json::{json, Value};
+
+use crate::gpt::{call_with_fallback, ChatRequest, Completion, LlmError};
+use crate::ledger::{lookup_account, parse_account};
+use crate::memory::{format_time, get_usage, tools_enabled};
+use crate::types::{Form, ToolCall, Usage, UsagePeriod, UsageSubject};
+
+/// Rounds of tool calls before the model has to answer.
+const MAX_TOOL_ROUNDS: usize = 4;
+
+/// Longest expression the calculator accepts.
+const MAX_EXPRESSION: usize = 200;
+
+pub type ToolFuture<'a> = Pin<Box<dyn Future<Output = Result<Value, String>> + 'a>>;
+
+/// Who the model is answering, for tools that look at the user's data.
+pub struct ToolContext {
+    pub user_id: i64,
+}
+
+/// A function the model may call. `parameters` is the JSON schema of the
+/// arguments, which arrive already parsed.
+pub trait Tool {
+    fn name(&self) -> &'static str;
+
+    fn description(&self) -> &'static str;
+
+    fn parameters(&self) -> Value;
+
+    fn call<'a>(&'a self, arguments: Value, context: &'a ToolContext) -> ToolFuture<'a>;
+}
+
+/// Every tool offered to the model.
+pub const TOOLS: &[&dyn Tool] = &[
+    &TimeTool,
+    &CycleBalanceTool,
+    &CalculatorTool,
+    &UnitConversionTool,
+    &UsageTool,
+    &LedgerTool,
+];
+
+/// The tools in OpenAI's `tools` format.
+pub fn tool_specs() -> Vec<Value> {
+    TOOLS
+        .iter()
+        .map(|tool| {
+            json!({
+                "type": "function",
+                "function": {
+                    "name": tool.name(),
+                    "description": tool.description(),
+                    "parameters": tool.parameters(),
+                }
+            })
+        })
+        .collect()
+}
+
+/// Runs one call and returns what to tell the model, errors included.
+pub async fn run_tool(call: &ToolCall, context: &ToolContext) -> String {
+    let Some(tool) = TOOLS.iter().find(|tool| tool.name() == call.function.name) else {
+        return json!({ "error": format!("Unknown tool {}.", call.function.name) }).to_string();
+    };
+    let arguments = match serde_json::from_str::<Value>(&call.function.arguments) {
+        Ok(arguments) => arguments,
+        Err(err) => return json!({ "error": format!("Invalid arguments: {}", err) }).to_string(),
+    };
+    match tool.call(arguments, context).await {
+        Ok(result) => result.to_string(),
+        Err(err) => json!({ "error": err }).to_string(),
+    }
+}
+
+/// Answers a chat request, running the tools the model asks for until it
+/// gives a final answer. Usage adds up over all rounds. Returns the model
+/// that answered and whether any tool was called.
+pub async fn chat_with_tools(
+    request: &ChatRequest,
+    request_id: String,
+    context: &ToolContext,
+    cycles: &mut u128,
+) -> Result<(Completion, String, bool), LlmError> {
+    let mut request = request.clone();
+    if tools_enabled() {
+        request.tools = tool_specs();
+    }
+    let mut usage = Usage::default();
+    let mut round = 0;
+    loop {
+        let id = if round == 0 { request_id.clone() } else { format!("{}-{}", request_id, round) };
+        let (mut completion, model) = call_with_fallback(&request, id, cycles).await?;
+        usage.prompt_tokens += completion.usage.prompt_tokens;
+        usage.completion_tokens += completion.usage.completion_tokens;
+        if completion.tool_calls.is_empty() || request.tools.is_empty() {
+            completion.usage = usage;
+            return Ok((completion, model, round > 0));
+        }
+        request.messages.push(Form {
+            role: "assistant".to_string(),
+            content: completion.content.clone(),
+            tool_calls: Some(completion.tool_calls.clone()),
+            ..Default::default()
+        });
+        for call in &completion.tool_calls {
+            request.messages.push(Form {
+                role: "tool".to_string(),
+                content: run_tool(call, context).await,
+                tool_call_id: Some(call.id.clone()),
+                ..Default::default()
+            });
+        }
+        round += 1;
+        if round == MAX_TOOL_ROUNDS {
+            // no more tools, the next answer is final
+            request.tools.clear();
+        }
+    }
+}
+
+fn string_argument(arguments: &Value, name: &str) -> Result<String, String> {
+    arguments[name]
+        .as_str()
+        .map(str::to_string)
+        .ok_or_else(|| format!("Missing argument {}.", name))
+}
+
+struct TimeTool;
+
+impl Tool for TimeTool {
+    fn name(&self) -> &'static str {
+        "get_current_time"
+    }
+
+    fn description(&self) -> &'static str {
+        "Get the current date and time in UTC, as seen by the Internet Computer."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({ "type": "object", "properties": {} })
+    }
+
+    fn call<'a>(&'a self, _arguments: Value, _context: &'a ToolContext) -> ToolFuture<'a> {
+        Box::pin(async move {
+            let time = ic_cdk::api::time();
+            Ok(json!({
+                "utc": format_time(time),
+                "unix_nanoseconds": time,
+            }))
+        })
+    }
+}
+
+struct CycleBalanceTool;
+
+impl Tool for CycleBalanceTool {
+    fn name(&self) -> &'static str {
+        "get_cycle_balance"
+    }
+
+    fn description(&self) -> &'static str {
+        "Get the cycle balance of the canister running this bot."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({ "type": "object", "properties": {} })
+    }
+
+    fn call<'a>(&'a self, _arguments: Value, _context: &'a ToolContext) -> ToolFuture<'a> {
+        Box::pin(async move {
+            let balance = ic_cdk::api::canister_balance128();
+            Ok(json!({
+                "cycles": balance.to_string(),
+                "trillion_cycles": balance as f64 / 1e12,
+            }))
+        })
+    }
+}
+
+struct CalculatorTool;
+
+impl Tool for CalculatorTool {
+    fn name(&self) -> &'static str {
+        "calculate"
+    }
+
+    fn description(&self) -> &'static str {
+        "Evaluate an arithmetic expression with + - * / % ^ and parentheses."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "expression": { "type": "string", "description": "For example (2 + 3) * 4 ^ 2" }
+            },
+            "required": ["expression"]
+        })
+    }
+
+    fn call<'a>(&'a self, arguments: Value, _context: &'a ToolContext) -> ToolFuture<'a> {
+        Box::pin(async move {
+            let expression = string_argument(&arguments, "expression")?;
+            let result = evaluate(&expression)?;
+            Ok(json!({ "expression": expression, "result": result }))
+        })
+    }
+}
+
+/// Evaluates arithmetic without anything else: numbers, `+ - * / % ^`,
+/// unary signs and parentheses.
+fn evaluate(expression: &str) -> Result<f64, String> {
+    if expression.len() > MAX_EXPRESSION {
+        return Err("Expression is too long.".to_string());
+    }
+    let mut parser = Parser {
+        chars: expression.chars().peekable(),
+    };
+    let value = parser.expression(0)?;
+    parser.skip_whitespace();
+    if let Some(c) = parser.chars.peek() {
+        return Err(format!("Unexpected {}.", c));
+    }
+    if value.is_finite() {
+        Ok(value)
+    } else {
+        Err("The result is not a finite number.".to_string())
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+}
+
+impl Parser<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.chars.next_if(|c| c.is_whitespace()).is_some() {}
+    }
+
+    fn next_operator(&mut self, operators: &[char]) -> Option<char> {
+        self.skip_whitespace();
+        self.chars.next_if(|c| operators.contains(c))
+    }
+
+    // `depth` bounds the recursion of parentheses and unary signs
+    fn expression(&mut self, depth: usize) -> Result<f64, String> {
+        let mut value = self.term(depth)?;
+        while let Some(operator) = self.next_operator(&['+', '-']) {
+            let right = self.term(depth)?;
+            value = if operator == '+' { value + right } else { value - right };
+        }
+        Ok(value)
+    }
+
+    fn term(&mut self, depth: usize) -> Result<f64, String> {
+        let mut value = self.unary(depth)?;
+        while let Some(operator) = self.next_operator(&['*', '/', '%']) {
+            let right = self.unary(depth)?;
+            if operator != '*' && right == 0.0 {
+                return Err("Division by zero.".to_string());
+            }
+            value = match operator {
+                '*' => value * right,
+                '/' => value / right,
+                _ => value % right,
+            };
+        }
+        Ok(value)
+    }
+
+    // `-2 ^ 2` is -4, but `2 ^ -1` is allowed
+    fn unary(&mut self, depth: usize) -> Result<f64, String> {
+        if depth > 50 {
+            return Err("Expression is nested too deeply.".to_string());
+        }
+        match self.next_operator(&['-', '+']) {
+            Some('-') => Ok(-self.unary(depth + 1)?),
+            Some(_) => self.unary(depth + 1),
+            None => self.power(depth),
+        }
+    }
+
+    fn power(&mut self, depth: usize) -> Result<f64, String> {
+        let base = self.primary(depth)?;
+        match self.next_operator(&['^']) {
+            Some(_) => Ok(base.powf(self.unary(depth + 1)?)),
+            None => Ok(base),
+        }
+    }
+
+    fn primary(&mut self, depth: usize) -> Result<f64, String> {
+        if self.next_operator(&['(']).is_some() {
+            let value = self.expression(depth + 1)?;
+            return match self.next_operator(&[')']) {
+                Some(_) => Ok(value),
+                None => Err("Missing ).".to_string()),
+            };
+        }
+        let mut number = String::new();
+        while let Some(c) = self.chars.next_if(|c| c.is_ascii_digit() || *c == '.') {
+            number.push(c);
+        }
+        match self.chars.peek() {
+            _ if !number.is_empty() => number.parse().map_err(|_| format!("Invalid number {}.", number)),
+            Some(c) => Err(format!("Unexpected {}.", c)),
+            None => Err("Unexpected end of the expression.".to_string()),
+        }
+    }
+}
+
+struct UnitConversionTool;
+
+/// Units with their dimension and size in that dimension's base unit.
+const UNITS: &[(&str, &str, f64)] = &[
+    ("mm", "length", 0.001),
+    ("cm", "length", 0.01),
+    ("m", "length", 1.0),
+    ("km", "length", 1_000.0),
+    ("in", "length", 0.0254),
+    ("ft", "length", 0.3048),
+    ("yd", "length", 0.9144),
+    ("mi", "length", 1_609.344),
+    ("mg", "mass", 0.001),
+    ("g", "mass", 1.0),
+    ("kg", "mass", 1_000.0),
+    ("t", "mass", 1_000_000.0),
+    ("oz", "mass", 28.349_523_125),
+    ("lb", "mass", 453.592_37),
+    ("ms", "time", 0.001),
+    ("s", "time", 1.0),
+    ("min", "time", 60.0),
+    ("h", "time", 3_600.0),
+    ("d", "time", 86_400.0),
+    ("week", "time", 604_800.0),
+    ("ml", "volume", 0.001),
+    ("l", "volume", 1.0),
+    ("gal", "volume", 3.785_411_784),
+    ("b", "data", 1.0),
+    ("kb", "data", 1e3),
+    ("mb", "data", 1e6),
+    ("gb", "data", 1e9),
+    ("tb", "data", 1e12),
+    ("kib", "data", 1_024.0),
+    ("mib", "data", 1_048_576.0),
+    ("gib", "data", 1_073_741_824.0),
+];
+
+/// Temperatures are offset, not only scaled, so they convert through kelvin.
+fn to_kelvin(value: f64, unit: &str) -> Option<f64> {
+    match unit {
+        "c" => Some(value + 273.15),
+        "f" => Some((value - 32.0) * 5.0 / 9.0 + 273.15),
+        "k" => Some(value),
+        _ => None,
+    }
+}
+
+fn from_kelvin(value: f64, unit: &str) -> Option<f64> {
+    match unit {
+        "c" => Some(value - 273.15),
+        "f" => Some((value - 273.15) * 9.0 / 5.0 + 32.0),
+        "k" => Some(value),
+        _ => None,
+    }
+}
+
+fn convert(value: f64, from: &str, to: &str) -> Result<f64, String> {
+    let (from, to) = (from.to_lowercase(), to.to_lowercase());
+    if let (Some(kelvin), Some(_)) = (to_kelvin(value, &from), from_kelvin(0.0, &to)) {
+        return Ok(from_kelvin(kelvin, &to).unwrap_or_default());
+    }
+    let find = |unit: &str| {
+        UNITS
+            .iter()
+            .find(|(name, _, _)| *name == unit)
+            .ok_or_else(|| format!("Unknown unit {}.", unit))
+    };
+    let (_, from_dimension, from_size) = find(&from)?;
+    let (_, to_dimension, to_size) = find(&to)?;
+    if from_dimension != to_dimension {
+        return Err(format!("Cannot convert {} to {}.", from_dimension, to_dimension));
+    }
+    Ok(value * from_size / to_size)
+}
+
+impl Tool for UnitConversionTool {
+    fn name(&self) -> &'static str {
+        "convert_units"
+    }
+
+    fn description(&self) -> &'static str {
+        "Convert a value between units of length, mass, time, volume, data size or temperature (C, F, K)."
+    }
+
+    fn parameters(&self) -> Value {
+        let units: Vec<&str> = UNITS.iter().map(|(name, _, _)| *name).chain(["c", "f", "k"]).collect();
+        json!({
+            "type": "object",
+            "properties": {
+                "value": { "type": "number" },
+                "from": { "type": "string", "enum": units },
+                "to": { "type": "string", "enum": units }
+            },
+            "required": ["value", "from", "to"]
+        })
+    }
+
+    fn call<'a>(&'a self, arguments: Value, _context: &'a ToolContext) -> ToolFuture<'a> {
+        Box::pin(async move {
+            let value = arguments["value"].as_f64().ok_or("Missing argument value.")?;
+            let from = string_argument(&arguments, "from")?;
+            let to = string_argument(&arguments, "to")?;
+            let result = convert(value, &from, &to)?;
+            Ok(json!({ "value": value, "from": from, "to": to, "result": result }))
+        })
+    }
+}
+
+struct UsageTool;
+
+impl Tool for UsageTool {
+    fn name(&self) -> &'static str {
+        "get_my_usage"
+    }
+
+    fn description(&self) -> &'static str {
+        "Get how many chats, images, tokens and cycles the user has used today and this month."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({ "type": "object", "properties": {} })
+    }
+
+    fn call<'a>(&'a self, _arguments: Value, context: &'a ToolContext) -> ToolFuture<'a> {
+        Box::pin(async move {
+            let today = get_usage(UsageSubject::User, context.user_id, UsagePeriod::Day);
+            let month = get_usage(UsageSubject::User, context.user_id, UsagePeriod::Month);
+            Ok(json!({ "today": today, "this_month": month }))
+        })
+    }
+}
+
+struct LedgerTool;
+
+impl Tool for LedgerTool {
+    fn name(&self) -> &'static str {
+        "get_ledger_account"
+    }
+
+    fn description(&self) -> &'static str {
+        "Get the token balance and recent transactions of a ledger account, given a principal or an ICP account id."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "account": { "type": "string", "description": "A principal or a 64 character account id" }
+            },
+            "required": ["account"]
+        })
+    }
+
+    fn call<'a>(&'a self, arguments: Value, _context: &'a ToolContext) -> ToolFuture<'a> {
+        Box::pin(async move {
+            let account = parse_account(&string_argument(&arguments, "account")?)?;
+            Ok(lookup_account(account, 10).await?.to_json())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arithmetic_follows_precedence() {
+        assert_eq!(evaluate("1 + 2 * 3"), Ok(7.0));
+        assert_eq!(evaluate("(1 + 2) * 3"), Ok(9.0));
+        assert_eq!(evaluate("2 ^ 3 ^ 2"), Ok(512.0));
+        assert_eq!(evaluate("-2 ^ 2"), Ok(-4.0));
+        assert_eq!(evaluate("2 ^ -1"), Ok(0.5));
+        assert_eq!(evaluate(" 7 % 4 - 10 / 4 "), Ok(0.5));
+    }
+
+    #[test]
+    fn bad_expressions_are_refused() {
+        assert_eq!(evaluate("1 / 0"), Err("Division by zero.".to_string()));
+        assert_eq!(evaluate("(1 + 2"), Err("Missing ).".to_string()));
+        assert_eq!(evaluate("1 +"), Err("Unexpected end of the expression.".to_string()));
+        assert_eq!(evaluate("2 x"), Err("Unexpected x.".to_string()));
+        assert_eq!(evaluate("1..2"), Err("Invalid number 1..2.".to_string()));
+        assert!(evaluate("10 ^ 400").is_err());
+        assert!(evaluate(&"(".repeat(60)).is_err());
+        assert!(evaluate(&"1+".repeat(150)).is_err());
+    }
+
+    #[test]
+    fn units_convert_within_a_dimension() {
+        assert_eq!(convert(1.5, "km", "m"), Ok(1_500.0));
+        assert_eq!(convert(2.0, "H", "min"), Ok(120.0));
+        assert_eq!(convert(1.0, "mib", "kib"), Ok(1_024.0));
+        assert!((convert(1.0, "lb", "kg").unwrap() - 0.453_592_37).abs() < 1e-12);
+        assert_eq!(convert(1.0, "kg", "m"), Err("Cannot convert mass to length.".to_string()));
+        assert_eq!(convert(1.0, "parsec", "m"), Err("Unknown unit parsec.".to_string()));
+    }
+
+    #[test]
+    fn temperatures_convert_through_kelvin() {
+        assert!((convert(100.0, "C", "F").unwrap() - 212.0).abs() < 1e-9);
+        assert!((convert(32.0, "f", "c").unwrap()).abs() < 1e-9);
+        assert!((convert(0.0, "k", "c").unwrap() + 273.15).abs() < 1e-9);
+        assert_eq!(convert(1.0, "c", "m"), Err("Unknown unit c.".to_string()));
+    }
+}
@@ -0,0 +1,164 @@
+use ic_cdk::api::management_canister::http_request::{
+    HttpResponse as HttpResponseCdk, TransformArgs as TransformArgsCdk,
+    TransformContext as TransformContextCdk,
+};
+use serde_json::{json, Value};
+
+/// Which response shape an outcall expects. It travels as the context bytes
+/// of the `TransformContext`, so `transform` knows what to keep.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum TransformKind {
+    /// Body passed through untouched.
+    Raw,
+    OpenAiChat,
+    OpenAiImage,
+    AnthropicMessages,
+    Telegram,
+}
+
+impl TransformKind {
+    fn as_bytes(&self) -> &'static [u8] {
+        match self {
+            TransformKind::Raw => b"raw",
+            TransformKind::OpenAiChat => b"openai_chat",
+            TransformKind::OpenAiImage => b"openai_image",
+            TransformKind::AnthropicMessages => b"anthropic_messages",
+            TransformKind::Telegram => b"telegram",
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        match bytes {
+            b"openai_chat" => TransformKind::OpenAiChat,
+            b"openai_image" => TransformKind::OpenAiImage,
+            b"anthropic_messages" => TransformKind::AnthropicMessages,
+            b"telegram" => TransformKind::Telegram,
+            _ => TransformKind::Raw,
+        }
+    }
+
+    pub fn context(&self) -> TransformContextCdk {
+        TransformContextCdk::from_name("transform".to_string(), self.as_bytes().to_vec())
+    }
+}
+
+/// Makes the responses of all replicas identical: headers are dropped and
+/// JSON bodies are reduced to the fields the bot reads, leaving out ids,
+/// timestamps and fingerprints that differ between replicas.
+pub fn transform_response(raw: TransformArgsCdk) -> HttpResponseCdk {
+    let kind = TransformKind::from_bytes(&raw.context);
+    let body = match kind {
+        TransformKind::Raw => raw.response.body,
+        _ => match serde_json::from_slice::<Value>(&raw.response.body) {
+            Ok(value) => strip(kind, value).to_string().into_bytes(),
+            // not JSON, e.g. a gateway error page
+            Err(_) => raw.response.body,
+        },
+    };
+    HttpResponseCdk {
+        status: raw.response.status,
+        body,
+        headers: vec![],
+    }
+}
+
+fn strip(kind: TransformKind, value: Value) -> Value {
+    let mut stripped = match kind {
+        TransformKind::OpenAiChat => {
+            let choices: Vec<Value> = value["choices"]
+                .as_array()
+                .map(|choices| {
+                    choices
+                        .iter()
+                        .map(|choice| {
+                            json!({
+                                "message": {
+                                    "content": choice["message"]["content"],
+                                    "refusal": choice["message"]["refusal"],
+                                    "tool_calls": normalize_tool_calls(&choice["message"]["tool_calls"]),
+                                },
+                                "finish_reason": choice["finish_reason"],
+                            })
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            json!({
+                "choices": choices,
+                "usage": {
+                    "prompt_tokens": value["usage"]["prompt_tokens"],
+                    "completion_tokens": value["usage"]["completion_tokens"],
+                },
+            })
+        }
+        TransformKind::OpenAiImage => {
+            let data: Vec<Value> = value["data"]
+                .as_array()
+                .map(|data| data.iter().map(|image| json!({ "url": image["url"] })).collect())
+                .unwrap_or_default();
+            json!({ "data": data })
+        }
+        TransformKind::AnthropicMessages => {
+            let content: Vec<Value> = value["content"]
+                .as_array()
+                .map(|content| {
+                    content
+                        .iter()
+                        .filter(|block| block["type"] == "text")
+                        .map(|block| json!({ "type": "text", "text": block["text"] }))
+                        .collect()
+                })
+                .unwrap_or_default();
+            json!({
+                "content": content,
+                "stop_reason": value["stop_reason"],
+                "usage": {
+                    "input_tokens": value["usage"]["input_tokens"],
+                    "output_tokens": value["usage"]["output_tokens"],
+                },
+            })
+        }
+        // Sent messages carry a message id and date that differ between
+        // replicas, so of the result only the fields `getMe` and
+        // `getChatMember` read are kept.
+        TransformKind::Telegram => json!({
+            "ok": value["ok"],
+            "description": value["description"],
+            "result": {
+                "username": value["result"]["username"],
+                "status": value["result"]["status"],
+            },
+        }),
+        TransformKind::Raw => value.clone(),
+    };
+    if !value["error"].is_null() {
+        stripped["error"] = json!({
+            "type": value["error"]["type"],
+            "code": value["error"]["code"],
+            "message": value["error"]["message"],
+        });
+    }
+    stripped
+}
+
+/// Tool call ids are random per response, so they are renumbered; the ids
+/// only have to match between a call and its result.
+fn normalize_tool_calls(tool_calls: &Value) -> Value {
+    match tool_calls.as_array() {
+        Some(tool_calls) => tool_calls
+            .iter()
+            .enumerate()
+            .map(|(index, call)| {
+                json!({
+                    "id": format!("call_{}", index),
+                    "type": call["type"],
+                    "function": {
+                        "name": call["function"]["name"],
+                        "arguments": call["function"]["arguments"],
+                    },
+                })
+            })
+            .collect(),
+        None => Value::Null,
+    }
+}
@@ -1,6 +1,46 @@
-use candid::CandidType;
+use std::borrow::Cow;
+
+use candid::{CandidType, Decode, Encode, Principal};
+use ic_stable_structures::storable::{Bound, Storable};
 use serde::{Deserialize, Serialize};
 
+/// Stores configuration records as Candid, so they can be kept whole in a
+/// `StableCell` and extended with optional fields later.
+macro_rules! impl_candid_storable {
+    ($($name:ty),*) => {
+        $(
+            impl Storable for $name {
+                fn to_bytes(&self) -> Cow<'_, [u8]> {
+                    Cow::Owned(Encode!(self).unwrap())
+                }
+
+                fn from_bytes(bytes: Cow<[u8]>) -> Self {
+                    Decode!(bytes.as_ref(), Self).unwrap()
+                }
+
+                const BOUND: Bound = Bound::Unbounded;
+            }
+        )*
+    };
+}
+
+impl_candid_storable!(
+    Config,
+    BackendConfig,
+    FallbackStep,
+    RetryPolicy,
+    RateLimits,
+    BudgetConfig,
+    BalanceSample,
+    ResponseCacheConfig,
+    LedgerConfig,
+    CreditConfig,
+    PremiumConfig,
+    RetentionConfig,
+    ChatSettings,
+    AccessRequest
+);
+
 /// A key-value pair for a HTTP header.
 #[derive(CandidType, Deserialize, Serialize, Clone, Debug)]
 #[derive(PartialEq)]
@@ -34,37 +74,516 @@ pub struct HttpResponse {
     pub upgrade: Option<bool>,
 }
 
-#[derive(CandidType, Deserialize)]
-pub struct TransformArgs {
-    /// Raw response from remote service, to be transformed
-    pub response: HttpResponse,
-
-    /// Context for response transformation
-    #[serde(with = "serde_bytes")]
-    pub context: Vec<u8>,
-}
-
-#[derive(Clone, Serialize, CandidType, Deserialize, PartialEq, Debug)]
+#[derive(Clone, Copy, Serialize, CandidType, Deserialize, PartialEq, Debug)]
 pub enum MessageType {
     Chat,
     Image
 }
 
 
+/// Where a conversation happens: a chat, and a thread inside it (0 when
+/// the chat has no threads).
+#[derive(Clone, Copy, Serialize, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug, Default)]
+pub struct Conversation {
+    pub chat_id: i64,
+    pub thread_id: i64,
+}
+
+impl Storable for Conversation {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(16);
+        // flip the sign bits so negative chat ids sort before positive ones
+        bytes.extend_from_slice(&((self.chat_id as u64) ^ (1 << 63)).to_be_bytes());
+        bytes.extend_from_slice(&((self.thread_id as u64) ^ (1 << 63)).to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Conversation {
+            chat_id: (u64::from_be_bytes(bytes[0..8].try_into().unwrap()) ^ (1 << 63)) as i64,
+            thread_id: (u64::from_be_bytes(bytes[8..16].try_into().unwrap()) ^ (1 << 63)) as i64,
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 16,
+        is_fixed_size: true,
+    };
+}
+
+/// Tokens billed by the provider for one turn.
+#[derive(Clone, Copy, Serialize, CandidType, Deserialize, Default, Debug, PartialEq)]
+pub struct Usage {
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+}
+
 #[derive(Clone, Serialize, CandidType, Deserialize)]
 pub struct Message {
+    pub conversation: Conversation,
+    pub user_id: i64,
     pub username: String,
     pub date: u64,
     pub types: MessageType,
     pub question: String,
     pub answer: String,
-    pub is_follow: bool
+    pub is_follow: bool,
+    pub usage: Usage,
+    /// The model that produced the answer.
+    pub model: String,
+}
+
+/// Fields of an update that `telegram_bot_raw` does not know about, read
+/// from the same webhook body.
+#[derive(Deserialize, Default)]
+pub struct UpdateExtras {
+    pub message: Option<MessageExtras>,
+    pub callback_query: Option<CallbackQueryExtras>,
+    pub pre_checkout_query: Option<PreCheckoutQuery>,
+}
+
+#[derive(Deserialize)]
+pub struct UserExtras {
+    pub id: i64,
 }
 
+/// Sent by Telegram before a payment goes through; it has to be answered
+/// within 10 seconds.
+#[derive(Deserialize)]
+pub struct PreCheckoutQuery {
+    pub id: String,
+    pub from: UserExtras,
+    pub currency: String,
+    pub total_amount: u32,
+    pub invoice_payload: String,
+}
+
+#[derive(Deserialize)]
+pub struct SuccessfulPayment {
+    pub currency: String,
+    pub total_amount: u32,
+    pub invoice_payload: String,
+    pub telegram_payment_charge_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct RefundedPayment {
+    pub telegram_payment_charge_id: String,
+}
+
+#[derive(Deserialize, Default)]
+pub struct CallbackQueryExtras {
+    pub message: Option<MessageExtras>,
+}
+
+#[derive(Deserialize, Default)]
+pub struct MessageExtras {
+    pub message_thread_id: Option<i64>,
+    #[serde(default)]
+    pub is_topic_message: bool,
+    pub successful_payment: Option<SuccessfulPayment>,
+    pub refunded_payment: Option<RefundedPayment>,
+}
+
+impl MessageExtras {
+    /// The forum topic the message was sent in, 0 outside of topics.
+    pub fn topic_id(&self) -> i64 {
+        if self.is_topic_message {
+            self.message_thread_id.unwrap_or_default()
+        } else {
+            0
+        }
+    }
+}
+
+/// Overrides set by chat admins; `None` falls back to the global config.
+#[derive(Clone, Serialize, CandidType, Deserialize, Default)]
+pub struct ChatSettings {
+    pub prompt: Option<String>,
+    pub model: Option<String>,
+}
+
+#[derive(Clone, Copy, Serialize, CandidType, Deserialize, PartialEq, Debug)]
+pub enum BackendKind {
+    Proxy,
+    OpenAi,
+    Anthropic,
+}
+
+/// Which LLM provider to call and how.
 #[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct BackendConfig {
+    pub kind: BackendKind,
+    /// Base URL without a trailing slash, e.g. `https://api.openai.com`.
+    pub base_url: String,
+    /// Extra headers sent with every request.
+    pub headers: Vec<HeaderField>,
+    /// Models chats may choose from besides the default model and, for
+    /// premium users, the premium model.
+    pub models: Vec<String>,
+}
+
+/// The API keys of one provider, used round-robin.
+#[derive(Clone, Default, Serialize, CandidType, Deserialize)]
+pub struct ApiKeys {
+    pub keys: Vec<String>,
+}
+
+impl Storable for ApiKeys {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// Whose usage a record counts.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum UsageSubject {
+    User,
+    Chat,
+    /// Everything the bot spent, with id 0.
+    Global,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, CandidType, Deserialize, Debug)]
+pub enum UsagePeriod {
+    Day,
+    Month,
+}
+
+/// Identifies one usage record: a user or chat over one day or month.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct UsageKey {
+    pub subject: UsageSubject,
+    pub id: i64,
+    pub period: UsagePeriod,
+    /// Days or months since the Unix epoch.
+    pub index: u32,
+}
+
+impl Storable for UsageKey {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        let mut bytes = Vec::with_capacity(14);
+        bytes.push(self.subject as u8);
+        // flip the sign bit so negative chat ids sort before positive ones
+        bytes.extend_from_slice(&((self.id as u64) ^ (1 << 63)).to_be_bytes());
+        bytes.push(self.period as u8);
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        UsageKey {
+            subject: match bytes[0] {
+                0 => UsageSubject::User,
+                1 => UsageSubject::Chat,
+                _ => UsageSubject::Global,
+            },
+            id: (u64::from_be_bytes(bytes[1..9].try_into().unwrap()) ^ (1 << 63)) as i64,
+            period: if bytes[9] == 0 { UsagePeriod::Day } else { UsagePeriod::Month },
+            index: u32::from_be_bytes(bytes[10..14].try_into().unwrap()),
+        }
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 14,
+        is_fixed_size: true,
+    };
+}
+
+/// What a user or chat consumed over a period.
+#[derive(Clone, Default, Serialize, CandidType, Deserialize, Debug)]
+pub struct UsageRecord {
+    pub requests: u64,
+    pub images: u64,
+    pub prompt_tokens: u64,
+    pub completion_tokens: u64,
+    pub cycles: u128,
+}
+
+impl Storable for UsageRecord {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct UserUsage {
+    pub user_id: i64,
+    pub usage: UsageRecord,
+}
+
+/// A token bucket: bursts of up to `capacity` requests, refilled by
+/// `refill_per_minute` tokens a minute.
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct BucketConfig {
+    pub capacity: u32,
+    pub refill_per_minute: u32,
+}
+
+/// Limits for a group of users. A quota of `None` is unlimited.
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct LimitTier {
+    pub name: String,
+    pub bucket: BucketConfig,
+    pub daily_chats: Option<u64>,
+    pub daily_images: Option<u64>,
+}
+
+/// Users without a tier of their own get the tier named `default`, or no
+/// limits when there is none.
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct RateLimits {
+    /// Shared by everybody in a group chat.
+    pub chat_bucket: BucketConfig,
+    pub tiers: Vec<LimitTier>,
+}
+
+/// Keeps the cycle balance from running out.
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct BudgetConfig {
+    /// Below this balance image generation is turned off.
+    pub reserve_cycles: u128,
+    /// Cycles completions may spend per day, unlimited when `None`.
+    pub daily_spend_cap: Option<u128>,
+    /// Telegram chat that receives the alerts.
+    pub alert_chat_id: Option<i64>,
+    /// Balances that trigger an alert when the balance drops below them.
+    pub warning_levels: Vec<u128>,
+    /// Alert when the canister burns more than this many cycles an hour.
+    pub burn_rate_alert: Option<u128>,
+}
+
+/// What the balance watcher saw last time.
+#[derive(Clone, Default, Serialize, CandidType, Deserialize)]
+pub struct BalanceSample {
+    pub balance: u128,
+    pub time: u64,
+    /// The lowest warning level already alerted about.
+    pub warned_level: Option<u128>,
+    /// Whether the burn rate was over the limit, which was alerted about.
+    pub burning: bool,
+}
+
+/// Whether and for how long answers to identical questions are reused.
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct ResponseCacheConfig {
+    pub enabled: bool,
+    pub ttl_secs: u64,
+    pub max_entries: u64,
+}
+
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct CachedResponse {
+    pub answer: String,
+    pub model: String,
+    pub created: u64,
+    /// What the answer cost, saved again on every hit.
+    pub cycles: u128,
+    /// Whose prompt it answered, so that forgetting the user drops it.
+    pub user_id: Option<i64>,
+}
+
+impl Storable for CachedResponse {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[derive(Clone, Default, Serialize, CandidType, Deserialize)]
+pub struct ResponseCacheStats {
+    pub entries: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub cycles_saved: u128,
+}
+
+/// The API an index canister speaks.
+#[derive(Clone, Copy, Serialize, CandidType, Deserialize, PartialEq, Debug)]
+pub enum IndexKind {
+    /// The ICP index, which also knows legacy account ids.
+    Icp,
+    /// The ICRC-1 index, e.g. of ckBTC.
+    Icrc,
+}
+
+/// The ledger behind `/balance`. Without an index canister transactions
+/// are not shown.
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct LedgerConfig {
+    pub ledger_canister_id: Principal,
+    pub index_canister_id: Option<Principal>,
+    pub index_kind: IndexKind,
+    pub symbol: String,
+    pub decimals: u8,
+}
+
+/// A token users can buy credits with.
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct PaymentToken {
+    pub symbol: String,
+    pub ledger_canister_id: Principal,
+    pub decimals: u8,
+    /// Credits per smallest unit of the token, e.g. per e8s.
+    pub credits_per_unit: u128,
+}
+
+/// Pay-per-use. One credit pays for one cycle; provider tokens are priced
+/// on top, since the provider bills them separately.
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct CreditConfig {
+    /// Whether completions are paid with credits.
+    pub enabled: bool,
+    pub tokens: Vec<PaymentToken>,
+    pub credits_per_llm_token: u128,
+}
+
+/// What `/premium` sells for Telegram Stars: `period_days` in the rate
+/// limit tier named `tier`, optionally with a better default model.
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct PremiumConfig {
+    pub enabled: bool,
+    pub price_stars: u32,
+    pub period_days: u32,
+    pub tier: String,
+    pub model: Option<String>,
+    pub title: String,
+    pub description: String,
+}
+
+/// An unknown user asking to be let in.
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct AccessRequest {
+    pub username: String,
+    /// Denied users can ask again a day later.
+    pub denied_at: Option<u64>,
+}
+
+/// The receipt of one Stars payment.
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct StarPayment {
+    pub user_id: i64,
+    pub stars: u32,
+    pub charge_id: String,
+    pub paid_at: u64,
+    /// The end of the premium period this payment bought; later payments
+    /// extend earlier ones.
+    pub premium_until: u64,
+    pub refunded_at: Option<u64>,
+}
+
+impl Storable for StarPayment {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// How long messages are kept. Every limit is optional; the oldest
+/// messages go first.
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct RetentionConfig {
+    pub max_age_days: Option<u32>,
+    pub max_messages_per_user: Option<u32>,
+    /// An estimate of the heap the messages take.
+    pub max_total_bytes: Option<u64>,
+    pub sweep_interval_secs: u64,
+}
+
+#[derive(Clone, Default, Serialize, CandidType, Deserialize)]
+pub struct RetentionStats {
+    pub sweeps: u64,
+    /// Timer calls; a sweep of a large store takes several.
+    pub ticks: u64,
+    pub in_progress: bool,
+    pub last_sweep_finished: Option<u64>,
+    pub deleted_by_age: u64,
+    pub deleted_by_count: u64,
+    pub deleted_by_size: u64,
+}
+
+/// Cycles spent on HTTPS outcalls since the last upgrade.
+#[derive(Clone, Default, Serialize, CandidType, Deserialize)]
+pub struct OutcallStats {
+    pub outcalls: u64,
+    pub cycles_attached: u128,
+    pub cycles_refunded: u128,
+}
+
+/// One step of the fallback chain: another model, optionally on another
+/// backend (the primary backend when `None`).
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct FallbackStep {
+    pub model: String,
+    pub backend: Option<BackendConfig>,
+}
+
+/// How often a failed completion is attempted, within the webhook call.
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct RetryPolicy {
+    /// Attempts in total, the first one included.
+    pub max_attempts: u32,
+    /// The wait after the first failed attempt, multiplied by
+    /// `backoff_multiplier` after every further one.
+    pub initial_backoff_secs: u64,
+    pub backoff_multiplier: u64,
+}
+
+impl RetryPolicy {
+    /// The waits between the attempts, in seconds.
+    pub fn backoffs(&self) -> impl Iterator<Item = u64> + '_ {
+        (0..self.max_attempts.saturating_sub(1))
+            .map(|retry| self.initial_backoff_secs.saturating_mul(self.backoff_multiplier.saturating_pow(retry)))
+    }
+}
+
+#[derive(Clone, Default, Serialize, CandidType, Deserialize)]
 pub struct Form {
     pub role: String,
-    pub content: String
+    pub content: String,
+    /// Tools the assistant asked for, in OpenAI's format.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<ToolCall>>,
+    /// Set on `tool` messages, which carry the result of a call.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_call_id: Option<String>,
+}
+
+#[derive(Clone, Serialize, CandidType, Deserialize, Debug)]
+pub struct ToolCall {
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub function: FunctionCall,
+}
+
+#[derive(Clone, Serialize, CandidType, Deserialize, Debug)]
+pub struct FunctionCall {
+    pub name: String,
+    /// JSON encoded arguments.
+    pub arguments: String,
 }
 
 #[derive(Clone, Serialize, CandidType, Deserialize)]
@@ -75,6 +594,121 @@ pub struct InitArg {
     pub prompts: Vec<Shortcut>
 }
 
+/// What a user may do, from least to most. Users without a role fall back
+/// to the username whitelist and the admin username of the init argument.
+#[derive(Clone, Copy, Serialize, CandidType, Deserialize, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Role {
+    /// Silently ignored.
+    Banned,
+    User,
+    /// Uses the premium tier, like users who paid with Stars.
+    Premium,
+    /// Can ban and unban users and view their usage.
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Role; 5] = [Role::Banned, Role::User, Role::Premium, Role::Moderator, Role::Admin];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Role::Banned => "banned",
+            Role::User => "user",
+            Role::Premium => "premium",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Role> {
+        Role::ALL.into_iter().find(|role| role.name().eq_ignore_ascii_case(name))
+    }
+}
+
+impl Storable for Role {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(vec![*self as u8])
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Role::ALL[bytes[0] as usize]
+    }
+
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 1,
+        is_fixed_size: true,
+    };
+}
+
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct UserRole {
+    pub user_id: i64,
+    pub role: Role,
+}
+
+/// An entry of the audit log: who gave whom which role, `None` when the
+/// role was taken away.
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct RoleChange {
+    pub user_id: i64,
+    pub role: Option<Role>,
+    /// `telegram:<user id>` or `principal:<principal>`.
+    pub by: String,
+    pub time: u64,
+}
+
+impl Storable for RoleChange {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// The default model and system prompt.
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct Config {
+    pub model: String,
+    pub prompt: String,
+}
+
+/// The global settings, as read and written by controllers.
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct BotConfig {
+    pub model: String,
+    pub prompt: String,
+    /// The username with admin rights besides the admins by role.
+    pub admin: String,
+    /// The username whitelist; empty lets everybody in.
+    pub usernames: Vec<String>,
+    pub shortcuts: Vec<Shortcut>,
+}
+
+/// A user with stored messages.
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct UserSummary {
+    pub user_id: i64,
+    pub username: String,
+    pub messages: u64,
+    pub last_message: u64,
+}
+
+#[derive(Clone, Serialize, CandidType, Deserialize)]
+pub struct CanisterStats {
+    pub cycles: u128,
+    pub heap_bytes: u64,
+    pub stable_bytes: u64,
+    pub users: u64,
+    pub conversations: u64,
+    pub messages: u64,
+    pub outcalls: OutcallStats,
+}
+
 #[derive(Clone, Serialize, CandidType, Deserialize)]
 pub struct Shortcut {
     pub shortcut: String,